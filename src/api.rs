@@ -0,0 +1,62 @@
+use serde_json;
+
+use models::{ChangeKind, User, Users};
+
+/// An entry as it appears in the API and exports.
+#[derive(Serialize)]
+pub struct Entry<'a> {
+    id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a User>,
+    /// Why the entry could not be parsed, if it couldn't.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+impl<'a> Entry<'a> {
+    pub fn new(id: &'a str, user: Result<&'a User, &'a str>) -> Entry<'a> {
+        Entry { id: id, user: user.ok(), error: user.err() }
+    }
+}
+
+#[derive(Serialize)]
+struct Changes<'a> {
+    /// Pass this back as `since` to get the changes after this point.
+    cursor: String,
+    /// If true, the client should throw away what it has, and replace it
+    /// with the entries in `added`.
+    reset: bool,
+    added: Vec<Entry<'a>>,
+    modified: Vec<Entry<'a>>,
+    deleted: Vec<&'a str>,
+}
+
+/// Lists the entries that changed since the given cursor, as JSON.
+///
+/// If the cursor is missing or too old, then every entry is returned instead.
+pub fn changes(users: &Users, since: Option<&str>) -> String {
+    let mut response = Changes {
+        cursor: users.generation().to_string(),
+        reset: false,
+        added: Vec::new(),
+        modified: Vec::new(),
+        deleted: Vec::new(),
+    };
+    let changes = since
+        .and_then(|since| users.resolve_cursor(since))
+        .and_then(|since| users.changes_since(since));
+    if let Some(changes) = changes {
+        for (id, kind) in changes {
+            match (kind, users.get(id)) {
+                (ChangeKind::Added, Some(user)) => response.added.push(Entry::new(id, user)),
+                (ChangeKind::Modified, Some(user)) => response.modified.push(Entry::new(id, user)),
+                (ChangeKind::Deleted, _) => response.deleted.push(id),
+                _ => {},
+            }
+        }
+    } else {
+        response.reset = true;
+        response.added = users.iter().map(|(id, user)| Entry::new(id, user)).collect();
+    }
+    serde_json::to_string(&response).unwrap()
+}
@@ -0,0 +1,255 @@
+//! Profile pictures, fetched and cached on the server so that visitors'
+//! browsers never have to contact GitHub or Gravatar themselves.
+
+use hyper::Client;
+use hyper::net::HttpsConnector;
+use hyper::status::StatusCode;
+use hyper_native_tls::NativeTlsClient;
+use md5;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use models::User;
+
+/// How long to keep a fetched picture before asking for it again, in seconds.
+const CACHE_TTL: u64 = 24 * 60 * 60;
+
+/// How long to wait before trying again after a picture couldn't be fetched,
+/// or wasn't there, in seconds.
+const MISSING_TTL: u64 = 60 * 60;
+
+/// How big to ask for pictures, in pixels.
+const SIZE: u32 = 160;
+
+/// Where profile pictures come from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    /// The picture on their GitHub profile.
+    GitHub,
+    /// The Gravatar for their email address, if they gave one.
+    Gravatar,
+    /// Don't fetch anything, and show identicons for everyone.
+    Offline,
+}
+
+impl Source {
+    /// Reads the source from the `KARKINOS_AVATAR_SOURCE` environment
+    /// variable, which can be `github` (the default), `gravatar` or
+    /// `offline`.
+    pub fn from_env() -> Source {
+        match env::var("KARKINOS_AVATAR_SOURCE").ok().as_ref().map(|s| &s[..]) {
+            None | Some("github") => Source::GitHub,
+            Some("gravatar") => Source::Gravatar,
+            Some("offline") => Source::Offline,
+            Some(other) => {
+                warn!("unknown avatar source {:?}; not fetching avatars", other);
+                Source::Offline
+            },
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Source::GitHub => "github",
+            Source::Gravatar => "gravatar",
+            Source::Offline => "offline",
+        }
+    }
+
+    /// Returns where to fetch someone's picture from, if anywhere.
+    fn url(&self, id: &str, user: &User) -> Option<String> {
+        match *self {
+            Source::GitHub => Some(format!("https://github.com/{}.png?size={}", id, SIZE)),
+            Source::Gravatar => user.email.as_ref().map(|email| {
+                let hash = md5::compute(email.trim().to_lowercase().as_bytes());
+                // Ask for a 404 rather than Gravatar's own default picture
+                format!("https://www.gravatar.com/avatar/{:x}?s={}&d=404", hash, SIZE)
+            }),
+            Source::Offline => None,
+        }
+    }
+}
+
+pub struct Avatar {
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+pub struct Avatars {
+    source: Source,
+    cache_dir: PathBuf,
+    /// `None` if TLS isn't available, in which case nothing can be fetched.
+    client: Option<Client>,
+    /// When each picture last couldn't be fetched.
+    missing: Mutex<HashMap<String, Instant>>,
+}
+
+impl Avatars {
+    /// Caches pictures in `KARKINOS_AVATAR_CACHE`, or in `avatars` under the
+    /// given directory if that isn't set.
+    pub fn from_env(root_dir: &Path) -> Avatars {
+        let cache_dir = env::var_os("KARKINOS_AVATAR_CACHE")
+            .map_or_else(|| root_dir.join("avatars"), PathBuf::from);
+        Avatars::new(Source::from_env(), cache_dir)
+    }
+
+    pub fn new(source: Source, cache_dir: PathBuf) -> Avatars {
+        let client = match NativeTlsClient::new() {
+            Ok(tls) => {
+                let mut client = Client::with_connector(HttpsConnector::new(tls));
+                client.set_read_timeout(Some(Duration::from_secs(10)));
+                client.set_write_timeout(Some(Duration::from_secs(10)));
+                Some(client)
+            },
+            Err(e) => {
+                warn!("not fetching avatars, as TLS isn't available: {}", e);
+                None
+            },
+        };
+        Avatars {
+            source: source,
+            cache_dir: cache_dir.join(source.name()),
+            client: client,
+            missing: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns someone's picture if they've asked for it to be shown, or an
+    /// identicon otherwise.
+    ///
+    /// A picture that can't be fetched is replaced by the last one we got,
+    /// or failing that an identicon, and isn't tried again for a while.
+    pub fn get(&self, id: &str, user: &User) -> Avatar {
+        let url = match self.source.url(id, user) {
+            Some(ref url) if user.show_avatar => url.clone(),
+            _ => return identicon(id),
+        };
+        let path = self.cache_dir.join(id);
+        let cached = read_cached(&path);
+        if let Some((ref avatar, fresh)) = cached {
+            if fresh {
+                return Avatar { content_type: avatar.content_type, body: avatar.body.clone() };
+            }
+        }
+        let recently_missing = self.missing.lock().unwrap().get(id)
+            .map_or(false, |&when| when.elapsed().as_secs() < MISSING_TTL);
+        if recently_missing {
+            return cached.map_or_else(|| identicon(id), |(avatar, _)| avatar);
+        }
+        let result = self.fetch(&url);
+        {
+            let mut missing = self.missing.lock().unwrap();
+            match result {
+                Ok(Some(_)) => { missing.remove(id); },
+                _ => { missing.insert(id.to_string(), Instant::now()); },
+            }
+        }
+        match result {
+            Ok(Some(body)) => {
+                if let Err(e) = fs::create_dir_all(&self.cache_dir)
+                    .and_then(|_| File::create(&path))
+                    .and_then(|mut file| file.write_all(&body))
+                {
+                    warn!("could not cache avatar for {}: {}", id, e);
+                }
+                Avatar { content_type: sniff(&body), body: body }
+            },
+            Ok(None) => {
+                // They don't have one, so forget the one we had
+                let _ = fs::remove_file(&path);
+                identicon(id)
+            },
+            Err(e) => {
+                warn!("could not fetch avatar for {}: {}", id, e);
+                cached.map_or_else(|| identicon(id), |(avatar, _)| avatar)
+            },
+        }
+    }
+
+    /// Downloads a picture, returning `None` if there isn't one.
+    fn fetch(&self, url: &str) -> io::Result<Option<Vec<u8>>> {
+        let client = self.client.as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "TLS isn't available"))?;
+        let mut response = client.get(url).send()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        match response.status {
+            StatusCode::Ok => {},
+            StatusCode::NotFound => return Ok(None),
+            status => return Err(io::Error::new(
+                io::ErrorKind::Other, format!("unexpected status {}", status))),
+        }
+        let mut body = Vec::new();
+        response.read_to_end(&mut body)?;
+        if sniff(&body) == "application/octet-stream" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an image"));
+        }
+        Ok(Some(body))
+    }
+}
+
+/// Reads a picture from the cache, and whether it's recent enough to use
+/// without checking for a new one.
+fn read_cached(path: &Path) -> Option<(Avatar, bool)> {
+    let mut file = File::open(path).ok()?;
+    let modified = file.metadata().and_then(|metadata| metadata.modified()).ok()?;
+    let fresh = SystemTime::now().duration_since(modified)
+        .map_or(true, |age| age.as_secs() < CACHE_TTL);
+    let mut body = Vec::new();
+    file.read_to_end(&mut body).ok()?;
+    Some((Avatar { content_type: sniff(&body), body: body }, fresh))
+}
+
+/// Works out what kind of image some bytes are.
+fn sniff(body: &[u8]) -> &'static str {
+    if body.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if body.starts_with(b"\xff\xd8\xff") {
+        "image/jpeg"
+    } else if body.starts_with(b"GIF87a") || body.starts_with(b"GIF89a") {
+        "image/gif"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// Draws a symmetric five by five pattern, in a colour picked from the id,
+/// so that everyone has a picture that stays the same.
+pub fn identicon(id: &str) -> Avatar {
+    let hash = md5::compute(id.as_bytes());
+    let hue = (u32::from(hash[0]) << 8 | u32::from(hash[1])) % 360;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 5 5\" width=\"{size}\" \
+         height=\"{size}\" shape-rendering=\"crispEdges\">\
+         <rect width=\"5\" height=\"5\" fill=\"#f0ece6\"/>\
+         <g fill=\"hsl({hue}, 55%, 50%)\">",
+        size = SIZE, hue = hue);
+    for row in 0..5 {
+        for column in 0..3 {
+            // Use a bit of the hash for each cell on the left, and mirror it
+            let bit = row * 3 + column;
+            if hash[2 + bit / 8] & (1 << (bit % 8)) != 0 {
+                svg.push_str(&format!("<rect x=\"{}\" y=\"{}\" width=\"1\" height=\"1\"/>",
+                                      column, row));
+                if column < 2 {
+                    svg.push_str(&format!("<rect x=\"{}\" y=\"{}\" width=\"1\" height=\"1\"/>",
+                                          4 - column, row));
+                }
+            }
+        }
+    }
+    svg.push_str("</g></svg>");
+    Avatar { content_type: "image/svg+xml", body: svg.into_bytes() }
+}
+
+#[test]
+fn identicons() {
+    let ferris = identicon("ferris");
+    assert_eq!(ferris.content_type, "image/svg+xml");
+    assert_eq!(ferris.body, identicon("ferris").body);
+    assert!(ferris.body != identicon("corro").body);
+}
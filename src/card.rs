@@ -0,0 +1,219 @@
+//! Preview images for profile pages, so that links shared in chat show who
+//! they're about.
+
+use image::{ColorType, Rgb, RgbImage};
+use image::png::PNGEncoder;
+use rusttype::{point, Font, Scale};
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use models::User;
+
+/// The size that OpenGraph and Twitter recommend for large previews.
+const WIDTH: u32 = 1200;
+const HEIGHT: u32 = 630;
+
+const MARGIN: f32 = 80.0;
+
+/// How much of someone's notes to show.
+const NOTES_WORDS: usize = 30;
+const NOTES_LINES: usize = 4;
+
+const BACKGROUND: [u8; 3] = [0xf7, 0xf1, 0xe8];
+const TEXT: [u8; 3] = [0x22, 0x22, 0x22];
+const MUTED: [u8; 3] = [0x77, 0x6e, 0x66];
+const CRAB: [u8; 3] = [0xb7, 0x41, 0x0e];
+
+pub struct Cards {
+    text: Font<'static>,
+    crab: Font<'static>,
+    cache: Mutex<Cache>,
+}
+
+/// The cards rendered since the data was last reloaded.
+#[derive(Default)]
+struct Cache {
+    generation: u64,
+    cards: HashMap<String, Arc<Vec<u8>>>,
+}
+
+impl Cards {
+    /// Loads the font named by `KARKINOS_CARD_FONT` for the text, or the
+    /// bundled DejaVu Serif if that isn't set, and the bundled Symbola subset
+    /// for the crab.
+    pub fn from_env(static_dir: &Path) -> io::Result<Cards> {
+        let text = env::var_os("KARKINOS_CARD_FONT")
+            .map_or_else(|| static_dir.join("dejavu-serif.ttf"), PathBuf::from);
+        Cards::load(&text, &static_dir.join("symbola-crab.ttf"))
+    }
+
+    fn load(text: &Path, crab: &Path) -> io::Result<Cards> {
+        Ok(Cards {
+            text: load_font(text)?,
+            crab: load_font(crab)?,
+            cache: Mutex::new(Cache::default()),
+        })
+    }
+
+    /// Returns someone's card as a PNG, rendering it if it hasn't been
+    /// already since the data was loaded.
+    pub fn get(&self, generation: u64, id: &str, user: &User) -> io::Result<Arc<Vec<u8>>> {
+        {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.generation != generation {
+                // Their details might have changed, so start over
+                cache.generation = generation;
+                cache.cards.clear();
+            }
+            if let Some(card) = cache.cards.get(id) {
+                return Ok(card.clone());
+            }
+        }
+        // Render without holding the lock, so that other cards aren't held up
+        let card = Arc::new(self.render(id, user)?);
+        let mut cache = self.cache.lock().unwrap();
+        if cache.generation == generation {
+            cache.cards.insert(id.to_string(), card.clone());
+        }
+        Ok(card)
+    }
+
+    /// Draws someone's card as a PNG.
+    fn render(&self, id: &str, user: &User) -> io::Result<Vec<u8>> {
+        let mut image = RgbImage::from_pixel(WIDTH, HEIGHT, Rgb(BACKGROUND));
+        let crab_size = 360.0;
+        draw_text(&mut image, &self.crab, "🦀", crab_size, CRAB,
+                  WIDTH as f32 - MARGIN - crab_size, (HEIGHT as f32 - crab_size) / 2.0);
+
+        let notes_width = WIDTH as f32 - 3.0 * MARGIN - crab_size;
+        let mut y = MARGIN;
+        let name = user.name.as_ref().map_or(id, |name| &name[..]);
+        y += draw_text(&mut image, &self.text, name, 72.0, TEXT, MARGIN, y) + 8.0;
+        y += draw_text(&mut image, &self.text, &format!("github.com/{}", id), 36.0, MUTED,
+                       MARGIN, y);
+        if let Some(ref irc) = user.irc {
+            y += draw_text(&mut image, &self.text, &format!("{} on IRC", irc), 36.0, MUTED,
+                           MARGIN, y);
+        }
+        if let Some(ref notes) = user.notes {
+            y += 24.0;
+            let words: Vec<&str> = notes.split_whitespace().take(NOTES_WORDS).collect();
+            let lines = wrap(&self.text, &words, 32.0, notes_width);
+            let truncated = lines.len() > NOTES_LINES ||
+                notes.split_whitespace().nth(NOTES_WORDS).is_some();
+            for (i, line) in lines.iter().take(NOTES_LINES).enumerate() {
+                let mut line = line.clone();
+                if truncated && i + 1 == NOTES_LINES.min(lines.len()) {
+                    line.push('…');
+                }
+                y += draw_text(&mut image, &self.text, &line, 32.0, TEXT, MARGIN, y) + 4.0;
+            }
+        }
+        draw_text(&mut image, &self.text, "Karkinos", 28.0, CRAB, MARGIN,
+                  HEIGHT as f32 - MARGIN - 28.0);
+
+        let mut png = Vec::new();
+        PNGEncoder::new(&mut png).encode(&image, WIDTH, HEIGHT, ColorType::RGB(8))?;
+        Ok(png)
+    }
+}
+
+fn load_font(path: &Path) -> io::Result<Font<'static>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    Font::from_bytes(bytes).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+    })
+}
+
+/// Draws a line of text with its top left corner at the given point, and
+/// returns how tall it was.
+fn draw_text(
+    image: &mut RgbImage, font: &Font, text: &str, size: f32, color: [u8; 3], x: f32, y: f32)
+    -> f32
+{
+    let scale = Scale::uniform(size);
+    let metrics = font.v_metrics(scale);
+    for glyph in font.layout(text, scale, point(x, y + metrics.ascent)) {
+        if let Some(bounds) = glyph.pixel_bounding_box() {
+            glyph.draw(|gx, gy, coverage| {
+                let (px, py) = (bounds.min.x + gx as i32, bounds.min.y + gy as i32);
+                if px >= 0 && py >= 0 && (px as u32) < WIDTH && (py as u32) < HEIGHT {
+                    let pixel = image.get_pixel_mut(px as u32, py as u32);
+                    for (channel, &target) in pixel.data.iter_mut().zip(&color) {
+                        let blended = f32::from(*channel) * (1.0 - coverage) +
+                            f32::from(target) * coverage;
+                        *channel = blended.round() as u8;
+                    }
+                }
+            });
+        }
+    }
+    metrics.ascent - metrics.descent + metrics.line_gap
+}
+
+/// Returns how wide a line of text would be.
+fn text_width(font: &Font, text: &str, size: f32) -> f32 {
+    font.layout(text, Scale::uniform(size), point(0.0, 0.0))
+        .last()
+        .map_or(0.0, |glyph| {
+            glyph.position().x + glyph.unpositioned().h_metrics().advance_width
+        })
+}
+
+/// Breaks words into lines that fit in the given width.
+fn wrap(font: &Font, words: &[&str], size: f32, width: f32) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut line = String::new();
+    for word in words {
+        let candidate = if line.is_empty() {
+            word.to_string()
+        } else {
+            format!("{} {}", line, word)
+        };
+        if !line.is_empty() && text_width(font, &candidate, size) > width {
+            lines.push(line);
+            line = word.to_string();
+        } else {
+            line = candidate;
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+#[test]
+fn png_size() {
+    use serde_json;
+
+    let static_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+    let cards = Cards::load(&static_dir.join("dejavu-serif.ttf"),
+                            &static_dir.join("symbola-crab.ttf")).unwrap();
+    let json = r#"{ "name": "Ferris", "irc": "ferris", "notes": "Loves the sea" }"#;
+    let user = User::from_value(serde_json::from_str(json).unwrap()).unwrap();
+    let png = cards.render("ferris", &user).unwrap();
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    // The header chunk comes first, and starts with the width and height
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(&png[16..24], &[0, 0, 0x04, 0xb0, 0, 0, 0x02, 0x76]);
+}
+
+#[test]
+fn cache() {
+    use serde_json;
+
+    let static_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+    let cards = Cards::load(&static_dir.join("dejavu-serif.ttf"),
+                            &static_dir.join("symbola-crab.ttf")).unwrap();
+    let user = User::from_value(serde_json::from_str(r#"{ "name": "Ferris" }"#).unwrap()).unwrap();
+    let first = cards.get(1, "ferris", &user).unwrap();
+    assert!(Arc::ptr_eq(&first, &cards.get(1, "ferris", &user).unwrap()));
+    // A reload throws the old cards away
+    assert!(!Arc::ptr_eq(&first, &cards.get(2, "ferris", &user).unwrap()));
+}
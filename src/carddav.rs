@@ -0,0 +1,702 @@
+//! A read-only CardDAV (RFC 6352) view of the directory, so that address book
+//! clients can search it directly.
+
+use iron::headers::Allow;
+use iron::method::Method;
+use iron::mime::Mime;
+use iron::prelude::*;
+use iron::status;
+use std::collections::BTreeSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+use xml::attribute::OwnedAttribute;
+use xml::reader::{self, EventReader, XmlEvent};
+
+use models::{User, Users};
+use search::{self, SearchIndex};
+use util::Escape;
+use vcard::{self, Property};
+
+const DAV: &'static str = "DAV:";
+const CARDDAV: &'static str = "urn:ietf:params:xml:ns:carddav";
+const CALENDARSERVER: &'static str = "http://calendarserver.org/ns/";
+
+/// The most cards that one query can return, so that a client can't dump
+/// the whole database with an empty filter.
+const MAX_RESULTS: usize = 100;
+
+/// The largest request body we'll read.
+const MAX_BODY_LEN: u64 = 64 * 1024;
+
+/// The principal, which is also the address book home.
+const ROOT_PATH: &'static str = "/carddav/";
+/// The one and only address book.
+const BOOK_PATH: &'static str = "/carddav/rustaceans/";
+
+/// Properties returned for collections when the client doesn't ask for
+/// anything in particular.
+const COLLECTION_PROPS: &'static [(&'static str, &'static str)] = &[
+    (DAV, "resourcetype"),
+    (DAV, "displayname"),
+    (DAV, "getetag"),
+    (CALENDARSERVER, "getctag"),
+];
+
+/// Properties returned for address objects when the client doesn't ask for
+/// anything in particular.
+const CARD_PROPS: &'static [(&'static str, &'static str)] = &[
+    (DAV, "resourcetype"),
+    (DAV, "getetag"),
+    (DAV, "getcontenttype"),
+];
+
+/// Properties returned by a report when the client doesn't ask for anything
+/// in particular.
+const REPORT_PROPS: &'static [(&'static str, &'static str)] = &[
+    (DAV, "getetag"),
+    (CARDDAV, "address-data"),
+];
+
+/// The vCard properties whose values come straight from fields in the search
+/// index. Others, like `URL`, are built from the data, so their text can't be
+/// looked up there.
+const INDEXED_PROPS: &'static [&'static str] = &["FN", "NICKNAME", "NOTE", "EMAIL"];
+
+/// A property name, as a namespace and local name.
+type PropName = (String, String);
+
+enum Target<'a> {
+    Root,
+    Book,
+    Card(&'a str, &'a User),
+}
+
+/// Answers a request, given its body from `read_query`.
+pub fn handle(r: &Request, users: &Users, query: &Query) -> IronResult<Response> {
+    let segments: Vec<String> = r.url.path().into_iter()
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_string)
+        .collect();
+    let target = match resolve(&segments, users) {
+        Some(target) => target,
+        None => return Ok(Response::with(status::NotFound)),
+    };
+    match r.method {
+        Method::Options => {
+            let mut response = Response::with(status::Ok);
+            response.headers.set_raw("DAV", vec![b"1, 3, addressbook".to_vec()]);
+            response.headers.set(allowed_methods());
+            Ok(response)
+        },
+        Method::Get | Method::Head => match target {
+            Target::Card(id, user) => {
+                let content_type: Mime = "text/vcard; charset=utf-8".parse().unwrap();
+                let card = vcard::vcard(id, user, &profile_url(r, id));
+                Ok(Response::with((status::Ok, content_type, card)))
+            },
+            _ => Ok(Response::with((status::Ok, "This is a read-only CardDAV address book.\n"))),
+        },
+        Method::Extension(ref name) if name == "PROPFIND" => {
+            let depth = depth(r);
+            let mut multistatus = MultiStatus::new();
+            match target {
+                Target::Root => {
+                    multistatus.collection(&target, users, query);
+                    if depth > 0 {
+                        multistatus.collection(&Target::Book, users, query);
+                    }
+                },
+                Target::Book => {
+                    multistatus.collection(&target, users, query);
+                    if depth > 0 {
+                        for (id, user) in users.iter() {
+                            if let Ok(user) = user {
+                                multistatus.card(id, user, &profile_url(r, id), query, CARD_PROPS);
+                            }
+                        }
+                    }
+                },
+                Target::Card(id, user) =>
+                    multistatus.card(id, user, &profile_url(r, id), query, CARD_PROPS),
+            }
+            Ok(multistatus.finish())
+        },
+        Method::Extension(ref name) if name == "REPORT" => {
+            match report(query, users, &|id: &str| profile_url(r, id)) {
+                Some(multistatus) => Ok(multistatus.finish()),
+                None => Ok(Response::with((status::Forbidden, "Unsupported report\n"))),
+            }
+        },
+        _ => {
+            // Everything else would modify the address book, which we don't
+            // allow
+            let mut response = Response::with(status::MethodNotAllowed);
+            response.headers.set(allowed_methods());
+            Ok(response)
+        },
+    }
+}
+
+/// Answers a `REPORT`, or returns `None` if it isn't one that we support.
+fn report(query: &Query, users: &Users, profile_url: &Fn(&str) -> String) -> Option<MultiStatus> {
+    let mut multistatus = MultiStatus::new();
+    match query.report.as_ref().map(|s| &s[..]) {
+        Some("addressbook-query") => {
+            let candidates = query.filter.candidates(users.search_index());
+            let ids: Vec<&str> = match candidates {
+                Some(ref ids) => ids.iter().map(|id| &id[..]).collect(),
+                None => users.iter().map(|(id, _)| id).collect(),
+            };
+            let mut found = 0;
+            for id in ids {
+                let user = match users.get(id) {
+                    Some(Ok(user)) => user,
+                    _ => continue,
+                };
+                let url = profile_url(id);
+                if !query.filter.matches(&vcard::properties(id, user, &url)) {
+                    continue;
+                }
+                if found == MAX_RESULTS {
+                    multistatus.truncated(BOOK_PATH);
+                    break;
+                }
+                found += 1;
+                multistatus.card(id, user, &url, query, REPORT_PROPS);
+            }
+        },
+        Some("addressbook-multiget") => {
+            for href in &query.hrefs {
+                let id = href.trim_right_matches('/').rsplit('/').next().unwrap_or("");
+                let id = id.trim_right_matches(".vcf");
+                match users.get(id) {
+                    Some(Ok(user)) =>
+                        multistatus.card(id, user, &profile_url(id), query, REPORT_PROPS),
+                    _ => multistatus.not_found(href),
+                }
+            }
+        },
+        _ => return None,
+    }
+    Some(multistatus)
+}
+
+fn resolve<'a>(segments: &'a [String], users: &'a Users) -> Option<Target<'a>> {
+    // The first segment is always "carddav"
+    match segments.len() {
+        1 => Some(Target::Root),
+        2 if segments[1] == "rustaceans" => Some(Target::Book),
+        3 if segments[1] == "rustaceans" && segments[2].ends_with(".vcf") => {
+            let id = segments[2].trim_right_matches(".vcf");
+            match users.get(id) {
+                Some(Ok(user)) => Some(Target::Card(id, user)),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+fn allowed_methods() -> Allow {
+    Allow(vec![
+        Method::Options,
+        Method::Get,
+        Method::Head,
+        Method::Extension("PROPFIND".to_string()),
+        Method::Extension("REPORT".to_string()),
+    ])
+}
+
+fn depth(r: &Request) -> u32 {
+    match r.headers.get_raw("Depth").and_then(|values| values.first()) {
+        Some(value) if &value[..] == b"0" => 0,
+        // Treat "infinity" (the default) like "1", since we're only two
+        // levels deep anyway
+        _ => 1,
+    }
+}
+
+fn profile_url(r: &Request, id: &str) -> String {
+    url_for!(r, "user", "id" => id).to_string()
+}
+
+fn card_href(id: &str) -> String {
+    format!("{}{}.vcf", BOOK_PATH, id)
+}
+
+/// The interesting parts of a `PROPFIND` or `REPORT` request body.
+#[derive(Debug, Default)]
+pub struct Query {
+    /// The name of the root element, which tells us which report to run.
+    report: Option<String>,
+    /// The properties asked for, or `None` for all of them.
+    props: Option<Vec<PropName>>,
+    filter: Filter,
+    /// The resources asked for by an `addressbook-multiget`.
+    hrefs: Vec<String>,
+}
+
+impl Query {
+    /// Returns the properties that should be in the response.
+    fn props(&self, defaults: &[(&str, &str)]) -> Vec<PropName> {
+        match self.props {
+            Some(ref props) if !props.is_empty() => props.clone(),
+            _ => defaults.iter().map(|&(ns, name)| (ns.to_string(), name.to_string())).collect(),
+        }
+    }
+}
+
+/// Reads and parses the request body. This should be done before looking at
+/// the directory, so that a slow client doesn't hold it up.
+pub fn read_query(r: &mut Request) -> IronResult<Query> {
+    let mut body = String::new();
+    itry!((&mut r.body).take(MAX_BODY_LEN + 1).read_to_string(&mut body), status::BadRequest);
+    if body.len() as u64 > MAX_BODY_LEN {
+        let error = io::Error::new(io::ErrorKind::InvalidData, "request body too large");
+        return Err(IronError::new(error, (status::PayloadTooLarge, "Request too large\n")));
+    }
+    if body.trim().is_empty() {
+        // An empty PROPFIND means "give me everything"
+        return Ok(Query::default());
+    }
+    Ok(itry!(parse_query(&body), (status::BadRequest, "Malformed XML\n")))
+}
+
+fn parse_query(body: &str) -> Result<Query, reader::Error> {
+    let mut query = Query::default();
+    // The local names of the elements we're inside
+    let mut stack: Vec<String> = Vec::new();
+    let mut text = String::new();
+    let mut in_text_match = false;
+    for event in EventReader::new(body.as_bytes()) {
+        match event? {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                let parent = stack.last().cloned();
+                let local = name.local_name.clone();
+                match (parent.as_ref().map(|s| &s[..]), &local[..]) {
+                    (None, _) => query.report = Some(local.clone()),
+                    // Properties asked for in `<prop>` directly under the root
+                    (Some("prop"), _) if stack.len() == 2 => {
+                        let namespace = name.namespace.clone().unwrap_or_else(String::new);
+                        query.props.get_or_insert_with(Vec::new).push((namespace, local.clone()));
+                    },
+                    (_, "allprop") => query.props = None,
+                    (_, "filter") => query.filter.all_of = attribute(&attributes, "test") == Some("allof"),
+                    (Some("filter"), "prop-filter") => query.filter.props.push(PropFilter {
+                        name: attribute(&attributes, "name").unwrap_or("").to_uppercase(),
+                        all_of: attribute(&attributes, "test") == Some("allof"),
+                        is_not_defined: false,
+                        text_matches: Vec::new(),
+                    }),
+                    (Some("prop-filter"), "is-not-defined") => {
+                        if let Some(filter) = query.filter.props.last_mut() {
+                            filter.is_not_defined = true;
+                        }
+                    },
+                    (Some("prop-filter"), "text-match") => {
+                        if let Some(filter) = query.filter.props.last_mut() {
+                            filter.text_matches.push(TextMatch {
+                                text: String::new(),
+                                match_type: MatchType::from_name(
+                                    attribute(&attributes, "match-type").unwrap_or("contains")),
+                                negate: attribute(&attributes, "negate-condition") == Some("yes"),
+                            });
+                            in_text_match = true;
+                        }
+                    },
+                    _ => {},
+                }
+                stack.push(local);
+                text.clear();
+            },
+            XmlEvent::Characters(s) | XmlEvent::CData(s) => text.push_str(&s),
+            XmlEvent::EndElement { name } => {
+                stack.pop();
+                match &name.local_name[..] {
+                    "text-match" if in_text_match => {
+                        let text_match = query.filter.props.last_mut()
+                            .and_then(|filter| filter.text_matches.last_mut());
+                        if let Some(text_match) = text_match {
+                            text_match.text = text.trim().to_string();
+                        }
+                        in_text_match = false;
+                    },
+                    "href" => query.hrefs.push(text.trim().to_string()),
+                    _ => {},
+                }
+            },
+            _ => {},
+        }
+    }
+    Ok(query)
+}
+
+fn attribute<'a>(attributes: &'a [OwnedAttribute], name: &str) -> Option<&'a str> {
+    attributes.iter()
+        .find(|attribute| attribute.name.local_name == name)
+        .map(|attribute| &attribute.value[..])
+}
+
+/// An `addressbook-query` filter.
+#[derive(Debug, Default)]
+struct Filter {
+    all_of: bool,
+    props: Vec<PropFilter>,
+}
+
+impl Filter {
+    /// Uses the search index to narrow down who could match, so that only
+    /// they need to be checked. Returns `None` if it can't, such as when
+    /// the filter is empty.
+    ///
+    /// The index only has whole words, so like the search page, a text match
+    /// is only found at the start of a word.
+    fn candidates(&self, index: &SearchIndex<String>) -> Option<BTreeSet<String>> {
+        combine(self.all_of, self.props.iter().map(|filter| filter.candidates(index)).collect())
+    }
+
+    fn matches(&self, props: &[Property]) -> bool {
+        if self.props.is_empty() {
+            true
+        } else if self.all_of {
+            self.props.iter().all(|filter| filter.matches(props))
+        } else {
+            self.props.iter().any(|filter| filter.matches(props))
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PropFilter {
+    name: String,
+    all_of: bool,
+    is_not_defined: bool,
+    text_matches: Vec<TextMatch>,
+}
+
+impl PropFilter {
+    fn candidates(&self, index: &SearchIndex<String>) -> Option<BTreeSet<String>> {
+        if self.is_not_defined || !INDEXED_PROPS.contains(&&self.name[..]) {
+            return None;
+        }
+        combine(self.all_of, self.text_matches.iter().map(|m| m.candidates(index)).collect())
+    }
+
+    fn matches(&self, props: &[Property]) -> bool {
+        let values: Vec<&str> = props.iter()
+            .filter(|prop| prop.name == self.name)
+            .map(|prop| &prop.value[..])
+            .collect();
+        if self.is_not_defined {
+            return values.is_empty();
+        }
+        if values.is_empty() {
+            return false;
+        }
+        let matches = |text_match: &TextMatch| values.iter().any(|value| text_match.matches(value));
+        if self.all_of {
+            self.text_matches.iter().all(matches)
+        } else {
+            self.text_matches.is_empty() || self.text_matches.iter().any(matches)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TextMatch {
+    text: String,
+    match_type: MatchType,
+    negate: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum MatchType {
+    Equals,
+    Contains,
+    StartsWith,
+    EndsWith,
+}
+
+impl MatchType {
+    fn from_name(name: &str) -> MatchType {
+        match name {
+            "equals" => MatchType::Equals,
+            "starts-with" => MatchType::StartsWith,
+            "ends-with" => MatchType::EndsWith,
+            _ => MatchType::Contains,
+        }
+    }
+}
+
+impl TextMatch {
+    fn candidates(&self, index: &SearchIndex<String>) -> Option<BTreeSet<String>> {
+        match self.match_type {
+            // The end of a value isn't necessarily the start of a word
+            MatchType::EndsWith => None,
+            _ if self.negate || search::terms(&self.text).is_empty() => None,
+            _ => Some(index.query_prefix(&self.text).into_iter().map(|(id, _)| id).collect()),
+        }
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        // Approximate the `i;unicode-casemap` collation
+        let value = value.to_lowercase();
+        let text = self.text.to_lowercase();
+        let result = match self.match_type {
+            MatchType::Equals => value == text,
+            MatchType::Contains => value.contains(&text[..]),
+            MatchType::StartsWith => value.starts_with(&text[..]),
+            MatchType::EndsWith => value.ends_with(&text[..]),
+        };
+        result != self.negate
+    }
+}
+
+/// Combines the candidates for each part of a filter. When all of them have
+/// to match, any part that narrows things down will do; otherwise, every
+/// part has to.
+fn combine(all_of: bool, sets: Vec<Option<BTreeSet<String>>>) -> Option<BTreeSet<String>> {
+    if all_of {
+        sets.into_iter().filter_map(|set| set).min_by_key(|set| set.len())
+    } else if sets.is_empty() {
+        None
+    } else {
+        let mut union = BTreeSet::new();
+        for set in sets {
+            union.extend(set?);
+        }
+        Some(union)
+    }
+}
+
+/// Builds up a `207 Multi-Status` response.
+struct MultiStatus(String);
+
+impl MultiStatus {
+    fn new() -> MultiStatus {
+        MultiStatus(format!(
+            concat!(
+                r#"<?xml version="1.0" encoding="utf-8"?>"#,
+                r#"<D:multistatus xmlns:D="{}" xmlns:C="{}" xmlns:CS="{}">"#),
+            DAV, CARDDAV, CALENDARSERVER))
+    }
+
+    fn collection(&mut self, target: &Target, users: &Users, query: &Query) {
+        let href = match *target {
+            Target::Root => ROOT_PATH,
+            _ => BOOK_PATH,
+        };
+        let is_book = match *target {
+            Target::Book => true,
+            _ => false,
+        };
+        let ctag = format!("\"{}\"", users.generation());
+        let props = query.props(COLLECTION_PROPS);
+        self.response(href, &props, |&(ref ns, ref name)| match (&ns[..], &name[..], is_book) {
+            (DAV, "resourcetype", false) => Some("<D:collection/>".to_string()),
+            (DAV, "resourcetype", true) => Some("<D:collection/><C:addressbook/>".to_string()),
+            (DAV, "displayname", false) => Some("Karkinos".to_string()),
+            (DAV, "displayname", true) => Some("Rustaceans".to_string()),
+            (DAV, "getetag", true) | (CALENDARSERVER, "getctag", true) =>
+                Some(Escape(&ctag).to_string()),
+            (DAV, "current-user-principal", _) | (DAV, "principal-URL", _) |
+            (CARDDAV, "addressbook-home-set", _) =>
+                Some(format!("<D:href>{}</D:href>", ROOT_PATH)),
+            (DAV, "current-user-privilege-set", _) =>
+                Some("<D:privilege><D:read/></D:privilege>".to_string()),
+            (DAV, "supported-report-set", true) => Some(concat!(
+                "<D:supported-report><D:report><C:addressbook-query/></D:report></D:supported-report>",
+                "<D:supported-report><D:report><C:addressbook-multiget/></D:report></D:supported-report>",
+            ).to_string()),
+            _ => None,
+        });
+    }
+
+    fn card(&mut self, id: &str, user: &User, profile_url: &str, query: &Query,
+            defaults: &[(&str, &str)]) {
+        let card = vcard::vcard(id, user, profile_url);
+        let etag = format!("\"{:016x}\"", {
+            let mut hasher = DefaultHasher::new();
+            card.hash(&mut hasher);
+            hasher.finish()
+        });
+        let props = query.props(defaults);
+        self.response(&card_href(id), &props, |&(ref ns, ref name)| match (&ns[..], &name[..]) {
+            (DAV, "resourcetype") => Some(String::new()),
+            (DAV, "getetag") => Some(Escape(&etag).to_string()),
+            (DAV, "getcontenttype") => Some("text/vcard; charset=utf-8".to_string()),
+            (CARDDAV, "address-data") => Some(Escape(&card).to_string()),
+            _ => None,
+        });
+    }
+
+    fn response<F>(&mut self, href: &str, props: &[PropName], mut value: F) where
+        F: FnMut(&PropName) -> Option<String>
+    {
+        let mut found = String::new();
+        let mut missing = String::new();
+        for prop in props {
+            match value(prop) {
+                Some(inner) => found.push_str(&element(prop, &inner)),
+                None => missing.push_str(&element(prop, "")),
+            }
+        }
+        self.0.push_str("<D:response>");
+        self.0.push_str(&format!("<D:href>{}</D:href>", Escape(href)));
+        if !found.is_empty() {
+            self.0.push_str(&format!(
+                "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>",
+                found));
+        }
+        if !missing.is_empty() {
+            self.0.push_str(&format!(
+                "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 404 Not Found</D:status></D:propstat>",
+                missing));
+        }
+        self.0.push_str("</D:response>");
+    }
+
+    fn not_found(&mut self, href: &str) {
+        self.0.push_str(&format!(
+            "<D:response><D:href>{}</D:href><D:status>HTTP/1.1 404 Not Found</D:status></D:response>",
+            Escape(href)));
+    }
+
+    /// Tells the client that there were more results than it was sent.
+    fn truncated(&mut self, href: &str) {
+        self.0.push_str(&format!(
+            concat!(
+                "<D:response><D:href>{}</D:href>",
+                "<D:status>HTTP/1.1 507 Insufficient Storage</D:status>",
+                "<D:error><D:number-of-matches-within-limits/></D:error>",
+                "</D:response>"),
+            Escape(href)));
+    }
+
+    fn finish(mut self) -> Response {
+        self.0.push_str("</D:multistatus>");
+        let content_type: Mime = "application/xml; charset=utf-8".parse().unwrap();
+        Response::with((status::MultiStatus, content_type, self.0))
+    }
+}
+
+/// Renders a property element, using our prefixes for the namespaces we know.
+fn element(&(ref ns, ref name): &PropName, inner: &str) -> String {
+    let (prefix, declaration) = match &ns[..] {
+        DAV => ("D:", String::new()),
+        CARDDAV => ("C:", String::new()),
+        CALENDARSERVER => ("CS:", String::new()),
+        "" => ("", String::new()),
+        ns => ("X:", format!(r#" xmlns:X="{}""#, Escape(ns))),
+    };
+    if inner.is_empty() {
+        format!("<{}{}{}/>", prefix, name, declaration)
+    } else {
+        format!("<{}{}{}>{}</{}{}>", prefix, name, declaration, inner, prefix, name)
+    }
+}
+
+#[test]
+fn addressbook_query() {
+    const BODY: &'static str = r#"<?xml version="1.0" encoding="utf-8" ?>
+        <C:addressbook-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:carddav">
+            <D:prop>
+                <D:getetag/>
+                <C:address-data>
+                    <C:prop name="FN"/>
+                </C:address-data>
+            </D:prop>
+            <C:filter test="anyof">
+                <C:prop-filter name="FN">
+                    <C:text-match collation="i;unicode-casemap" match-type="starts-with">bo</C:text-match>
+                </C:prop-filter>
+                <C:prop-filter name="EMAIL">
+                    <C:text-match collation="i;unicode-casemap">example.org</C:text-match>
+                </C:prop-filter>
+            </C:filter>
+        </C:addressbook-query>"#;
+    let query = parse_query(BODY).unwrap();
+    assert_eq!(query.report, Some("addressbook-query".to_string()));
+    assert_eq!(query.props, Some(vec![
+        (DAV.to_string(), "getetag".to_string()),
+        (CARDDAV.to_string(), "address-data".to_string()),
+    ]));
+    assert_eq!(query.filter.props.len(), 2);
+    let bors = vec![Property::text("FN", "Bors")];
+    assert!(query.filter.matches(&bors));
+    let ferris = vec![Property::text("FN", "Ferris"), Property::text("EMAIL", "ferris@example.org")];
+    assert!(query.filter.matches(&ferris));
+    let nobody = vec![Property::text("FN", "Nobody")];
+    assert!(!query.filter.matches(&nobody));
+}
+
+#[cfg(test)]
+fn test_users() -> Users {
+    Users::from_json(&[
+        ("bors", r#"{ "name": "Bors", "email": "bors@rust-lang.org" }"#),
+        ("ferris", r#"{ "name": "Ferris the Crab", "email": "ferris@example.org" }"#),
+        ("corro", r#"{ "name": "Corro" }"#),
+    ])
+}
+
+#[test]
+fn report_round_trip() {
+    let users = test_users();
+    let profile_url = |id: &str| format!("https://karkinos.example/user/{}", id);
+    let report_for = |filter: &str| {
+        let body = format!(r#"<?xml version="1.0" encoding="utf-8" ?>
+            <C:addressbook-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:carddav">
+                <D:prop><D:getetag/></D:prop>
+                <C:filter test="anyof">{}</C:filter>
+            </C:addressbook-query>"#, filter);
+        report(&parse_query(&body).unwrap(), &users, &profile_url).unwrap().0
+    };
+
+    let found = report_for(r#"
+        <C:prop-filter name="FN">
+            <C:text-match match-type="starts-with">cra</C:text-match>
+        </C:prop-filter>
+        <C:prop-filter name="EMAIL">
+            <C:text-match>rust-lang.org</C:text-match>
+        </C:prop-filter>"#);
+    assert!(found.contains("<D:href>/carddav/rustaceans/bors.vcf</D:href>"));
+    assert!(!found.contains("ferris.vcf"));
+    assert!(!found.contains("corro.vcf"));
+    let found = report_for(r#"
+        <C:prop-filter name="FN">
+            <C:text-match match-type="contains">crab</C:text-match>
+        </C:prop-filter>"#);
+    assert!(found.contains("<D:href>/carddav/rustaceans/ferris.vcf</D:href>"));
+    assert!(!found.contains("bors.vcf"));
+    // URLs aren't in the search index, but can still be matched
+    let found = report_for(r#"
+        <C:prop-filter name="URL">
+            <C:text-match match-type="contains">github.com/corro</C:text-match>
+        </C:prop-filter>"#);
+    assert!(found.contains("<D:href>/carddav/rustaceans/corro.vcf</D:href>"));
+    assert!(!found.contains("ferris.vcf"));
+    // An empty filter matches everyone, up to the limit
+    let found = report_for("");
+    assert_eq!(found.matches("<D:href>").count(), 3);
+    assert!(!found.contains("507"));
+}
+
+#[test]
+fn result_limit() {
+    let entries: Vec<(String, &str)> = (0..MAX_RESULTS + 1)
+        .map(|i| (format!("crab{}", i), r#"{ "name": "Crab" }"#))
+        .collect();
+    let entries: Vec<(&str, &str)> = entries.iter()
+        .map(|&(ref id, json)| (&id[..], json))
+        .collect();
+    let users = Users::from_json(&entries);
+    let body = r#"<?xml version="1.0" encoding="utf-8" ?>
+        <C:addressbook-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:carddav">
+            <D:prop><D:getetag/></D:prop>
+        </C:addressbook-query>"#;
+    let found = report(&parse_query(body).unwrap(), &users, &|id: &str| id.to_string()).unwrap().0;
+    assert_eq!(found.matches(".vcf</D:href>").count(), MAX_RESULTS);
+    assert!(found.contains("<D:href>/carddav/rustaceans/</D:href>\
+                            <D:status>HTTP/1.1 507 Insufficient Storage</D:status>"));
+}
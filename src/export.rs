@@ -0,0 +1,145 @@
+use serde_json;
+use std::io::{self, Write};
+
+use api::Entry;
+use models::{User, Users};
+
+/// A format that the whole database can be dumped in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// A single JSON array.
+    Json,
+    /// One JSON object per line.
+    JsonLines,
+    /// One row per entry, with a header row.
+    Csv,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "json" => Some(Format::Json),
+            "jsonl" => Some(Format::JsonLines),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::JsonLines => "jsonl",
+            Format::Csv => "csv",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::JsonLines => "application/x-ndjson",
+            Format::Csv => "text/csv; charset=utf-8",
+        }
+    }
+}
+
+/// The columns of the CSV export, in order.
+///
+/// Fields added by private layers vary from entry to entry, so they are only
+/// included in the JSON formats.
+const CSV_COLUMNS: &'static [&'static str] = &[
+    "id", "name", "irc", "irc_channels", "show_avatar", "email", "discourse",
+    "reddit", "twitter", "blog", "website", "notes",
+];
+
+/// Writes every entry in the given format.
+///
+/// Entries that failed to parse are skipped, unless `include_errors` is set.
+pub fn export<W: Write>(
+    out: &mut W, users: &Users, format: Format, include_errors: bool) -> io::Result<()>
+{
+    let entries = users.iter()
+        .filter(|&(_, ref user)| include_errors || user.is_ok())
+        .map(|(id, user)| Entry::new(id, user));
+    match format {
+        Format::Json => {
+            let entries: Vec<Entry> = entries.collect();
+            serde_json::to_writer(&mut *out, &entries)?;
+            writeln!(out)?;
+        },
+        Format::JsonLines => {
+            for entry in entries {
+                serde_json::to_writer(&mut *out, &entry)?;
+                writeln!(out)?;
+            }
+        },
+        Format::Csv => {
+            let mut header: Vec<&str> = CSV_COLUMNS.to_vec();
+            if include_errors {
+                header.push("error");
+            }
+            write_csv_row(out, &header[..])?;
+            for (id, user) in users.iter() {
+                match user {
+                    Ok(user) => {
+                        let mut row = csv_row(id, user);
+                        if include_errors {
+                            row.push(String::new());
+                        }
+                        write_csv_row(out, &row[..])?;
+                    },
+                    Err(error) if include_errors => {
+                        let mut row = vec![String::new(); CSV_COLUMNS.len()];
+                        row[0] = id.to_string();
+                        row.push(error.to_string());
+                        write_csv_row(out, &row[..])?;
+                    },
+                    Err(_) => {},
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+fn csv_row(id: &str, user: &User) -> Vec<String> {
+    fn field(s: &Option<String>) -> String {
+        s.clone().unwrap_or_else(String::new)
+    }
+    vec![
+        id.to_string(),
+        field(&user.name),
+        field(&user.irc),
+        user.irc_channels.join(" "),
+        user.show_avatar.to_string(),
+        field(&user.email),
+        field(&user.discourse),
+        field(&user.reddit),
+        field(&user.twitter),
+        field(&user.blog),
+        field(&user.website),
+        field(&user.notes),
+    ]
+}
+
+fn write_csv_row<W: Write, S: AsRef<str>>(out: &mut W, row: &[S]) -> io::Result<()> {
+    for (i, cell) in row.iter().enumerate() {
+        if i > 0 {
+            write!(out, ",")?;
+        }
+        let cell = cell.as_ref();
+        if cell.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+            write!(out, "\"{}\"", cell.replace('"', "\"\""))?;
+        } else {
+            write!(out, "{}", cell)?;
+        }
+    }
+    // RFC 4180 says lines end with CRLF
+    write!(out, "\r\n")
+}
+
+#[test]
+fn csv_quoting() {
+    let mut out = Vec::new();
+    write_csv_row(&mut out, &["bors", "Bors, the bot", "says \"r+\""][..]).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "bors,\"Bors, the bot\",\"says \"\"r+\"\"\"\r\n");
+}
@@ -0,0 +1,64 @@
+use chrono::Utc;
+use iron::prelude::*;
+use std::fmt::{self, Write};
+
+use history::History;
+use models::Users;
+use util::Escape;
+
+/// Renders an Atom feed of the latest additions and updates.
+pub fn atom(r: &Request, history: &History, users: &Users) -> String {
+    let mut out = String::new();
+    // Writing to a `String` never fails
+    write_atom(&mut out, r, history, users).unwrap();
+    out
+}
+
+fn write_atom<W: Write>(out: &mut W, r: &Request, history: &History, users: &Users) -> fmt::Result {
+    let changes = history.recent_changes();
+    let updated = changes.first()
+        .map(|change| change.revision.time)
+        .unwrap_or_else(Utc::now);
+    let feed_url = url_for!(r, "feed").to_string();
+    writeln!(out, r#"<?xml version="1.0" encoding="utf-8"?>"#)?;
+    writeln!(out, r#"<feed xmlns="http://www.w3.org/2005/Atom">"#)?;
+    writeln!(out, "<title>New and updated Rustaceans - Karkinos</title>")?;
+    writeln!(out, "<id>{}</id>", Escape(&feed_url))?;
+    writeln!(out, r#"<link rel="self" href="{}"/>"#, Escape(&feed_url))?;
+    writeln!(out, r#"<link rel="alternate" type="text/html" href="{}"/>"#,
+             Escape(&url_for!(r, "home").to_string()))?;
+    writeln!(out, "<updated>{}</updated>", updated.to_rfc3339())?;
+    for change in changes {
+        let name = match users.get(&change.id) {
+            Some(Ok(user)) => user.name.as_ref().map(|name| format!("{} ({})", name, change.id)),
+            _ => None,
+        }.unwrap_or_else(|| change.id.clone());
+        let user_url = url_for!(r, "user", "id" => &change.id[..]).to_string();
+        let history_url = url_for!(r, "user_history", "id" => &change.id[..]).to_string();
+        let title = if change.added {
+            format!("New: {}", name)
+        } else {
+            format!("Updated: {}", name)
+        };
+        let fields = change.fields.iter()
+            .map(|field| &field.field[..])
+            .collect::<Vec<_>>()
+            .join(", ");
+        let summary = if fields.is_empty() {
+            change.revision.summary.clone()
+        } else if change.added {
+            format!("Added with {}. {}", fields, change.revision.summary)
+        } else {
+            format!("Changed {}. {}", fields, change.revision.summary)
+        };
+        writeln!(out, "<entry>")?;
+        writeln!(out, "<id>{}#{}</id>", Escape(&history_url), Escape(&change.revision.commit))?;
+        writeln!(out, "<title>{}</title>", Escape(&title))?;
+        writeln!(out, r#"<link rel="alternate" type="text/html" href="{}"/>"#, Escape(&user_url))?;
+        writeln!(out, "<updated>{}</updated>", change.revision.time.to_rfc3339())?;
+        writeln!(out, "<author><name>{}</name></author>", Escape(&change.id))?;
+        writeln!(out, "<summary>{}</summary>", Escape(&summary))?;
+        writeln!(out, "</entry>")?;
+    }
+    writeln!(out, "</feed>")
+}
@@ -0,0 +1,116 @@
+//! A finger (RFC 1288) server, for looking people up from a terminal.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use limit::{Connections, Deadline};
+use models::Users;
+use text::{self, Flavor};
+
+/// The longest query we're willing to read.
+const MAX_QUERY_LEN: usize = 512;
+
+/// How many results a `/W` search returns.
+const MAX_RESULTS: usize = 20;
+
+/// How many queries are answered at once. Connections beyond this are
+/// closed straight away.
+const MAX_CONNECTIONS: usize = 32;
+
+/// How long a client has to send the whole query.
+const READ_TIMEOUT: u64 = 10;
+
+/// Listens for finger queries on the given address, in a background thread.
+///
+/// `profile_url` gives the address of a user's page on the web site.
+pub fn start(
+    addr: &str, users: Arc<RwLock<Users>>, profile_url: fn(&str) -> String) -> io::Result<()>
+{
+    let listener = TcpListener::bind(addr)?;
+    info!("finger server listening on {}", addr);
+    let connections = Connections::new(MAX_CONNECTIONS);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let slot = match connections.take() {
+                        Some(slot) => slot,
+                        None => {
+                            warn!("finger connection dropped: too many connections");
+                            continue;
+                        },
+                    };
+                    let users = users.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle(stream, &users, profile_url) {
+                            warn!("finger error: {}", e);
+                        }
+                        drop(slot);
+                    });
+                },
+                Err(e) => error!("finger connection failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle(
+    stream: TcpStream, users: &RwLock<Users>, profile_url: fn(&str) -> String) -> io::Result<()>
+{
+    let mut stream = Deadline::new(stream, Duration::from_secs(READ_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new((&mut stream).take(MAX_QUERY_LEN as u64)).read_line(&mut line)?;
+    let body = respond(&line, &users.read().unwrap(), &profile_url);
+    // The protocol wants CRLF line endings, and nothing unprintable
+    stream.write_all(text::printable(&body).replace('\n', "\r\n").as_bytes())
+}
+
+fn respond(line: &str, users: &Users, profile_url: &Fn(&str) -> String) -> String {
+    let (verbose, query) = parse_query(line);
+    if query.contains('@') {
+        return "Sorry, finger forwarding is not supported.\n".to_string();
+    }
+    if query.is_empty() {
+        return format!(
+            concat!(
+                "Karkinos: a directory of {} Rustaceans\n\n",
+                "  finger <github-id>@<host>     show someone's details\n",
+                "  finger \"/W <query>\"@<host>    search for people\n"),
+            users.len());
+    }
+    if verbose {
+        let (results, correction) = users.search(query);
+        let results = results.into_iter()
+            .take(MAX_RESULTS)
+            .map(|(id, weight)| (users.get(&id).unwrap(), id, weight));
+        text::search_results(profile_url, Flavor::Plain, query, results, correction)
+    } else {
+        match users.get(query) {
+            Some(Ok(user)) => text::user(profile_url, Flavor::Plain, query, user),
+            Some(Err(error)) => text::user_error(Flavor::Plain, query, error),
+            None => text::user_not_found(Flavor::Plain, query),
+        }
+    }
+}
+
+/// Splits a query line into the `/W` switch and the rest.
+fn parse_query(line: &str) -> (bool, &str) {
+    let line = line.trim();
+    if line.starts_with("/W") || line.starts_with("/w") {
+        (true, line[2..].trim())
+    } else {
+        (false, line)
+    }
+}
+
+#[test]
+fn queries() {
+    assert_eq!(parse_query("\r\n"), (false, ""));
+    assert_eq!(parse_query("bors\r\n"), (false, "bors"));
+    assert_eq!(parse_query("/W crab people\r\n"), (true, "crab people"));
+    assert_eq!(parse_query("/W\r\n"), (true, ""));
+}
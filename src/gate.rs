@@ -0,0 +1,94 @@
+use std::env;
+use std::fmt::{self, Display, Formatter};
+
+use models::Users;
+
+/// Decides whether freshly loaded data is sane enough to replace what we're
+/// serving now.
+///
+/// A bad commit upstream can break a large number of entries at once. When
+/// that happens, we'd rather keep serving stale data than broken data.
+#[derive(Debug)]
+pub struct ReloadGate {
+    /// The largest fraction of entries that may fail to parse.
+    max_error_rate: f64,
+    /// The largest fraction by which the number of entries may drop.
+    max_shrink: f64,
+}
+
+impl ReloadGate {
+    /// Reads the thresholds from the `KARKINOS_MAX_ERROR_RATE` and
+    /// `KARKINOS_MAX_SHRINK` environment variables, falling back to 5% and
+    /// 10% respectively.
+    pub fn from_env() -> ReloadGate {
+        ReloadGate {
+            max_error_rate: fraction_from_env("KARKINOS_MAX_ERROR_RATE", 0.05),
+            max_shrink: fraction_from_env("KARKINOS_MAX_SHRINK", 0.10),
+        }
+    }
+
+    pub fn check(&self, old: &Users, new: &Users) -> Result<(), Rejection> {
+        let errors = new.errors().count();
+        if errors as f64 > self.max_error_rate * new.len() as f64 {
+            return Err(Rejection::TooManyErrors { errors: errors, total: new.len() });
+        }
+        if (new.len() as f64) < (1.0 - self.max_shrink) * old.len() as f64 {
+            return Err(Rejection::TooFewEntries { before: old.len(), after: new.len() });
+        }
+        Ok(())
+    }
+}
+
+fn fraction_from_env(name: &str, default: f64) -> f64 {
+    match env::var(name).ok().map(|s| s.parse::<f64>()) {
+        Some(Ok(x)) if 0.0 <= x && x <= 1.0 => x,
+        Some(_) => {
+            warn!("{} should be a number between 0 and 1; using {}", name, default);
+            default
+        },
+        None => default,
+    }
+}
+
+/// The reason an update was turned away.
+#[derive(Debug)]
+pub enum Rejection {
+    TooManyErrors { errors: usize, total: usize },
+    TooFewEntries { before: usize, after: usize },
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Rejection::TooManyErrors { errors, total } =>
+                write!(f, "{} of {} entries failed to parse", errors, total),
+            Rejection::TooFewEntries { before, after } =>
+                write!(f, "number of entries dropped from {} to {}", before, after),
+        }
+    }
+}
+
+#[test]
+fn checks() {
+    let gate = ReloadGate { max_error_rate: 0.25, max_shrink: 0.5 };
+    let entries = |good: usize, bad: usize| {
+        let ids: Vec<String> = (0..good + bad).map(|i| format!("crab{}", i)).collect();
+        let entries: Vec<(&str, &str)> = ids.iter().enumerate()
+            .map(|(i, id)| (&id[..], if i < good { "{}" } else { r#"{ "name": 42 }"# }))
+            .collect();
+        Users::from_json(&entries)
+    };
+    let old = entries(4, 0);
+
+    // A few errors, and a few people leaving, are fine
+    assert!(gate.check(&old, &entries(3, 1)).is_ok());
+    assert!(gate.check(&old, &entries(2, 0)).is_ok());
+    match gate.check(&old, &entries(2, 2)) {
+        Err(Rejection::TooManyErrors { errors: 2, total: 4 }) => {},
+        result => panic!("unexpected {:?}", result),
+    }
+    match gate.check(&old, &entries(1, 0)) {
+        Err(Rejection::TooFewEntries { before: 4, after: 1 }) => {},
+        result => panic!("unexpected {:?}", result),
+    }
+}
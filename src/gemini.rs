@@ -0,0 +1,392 @@
+//! A Gemini frontend, serving the directory as gemtext over TLS.
+
+use iron::url::Url;
+use iron::url::percent_encoding::percent_decode;
+use native_tls::{Identity, TlsAcceptor};
+use pulldown_cmark::{Event, Parser, Tag};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use limit::{Connections, Deadline};
+use models::{User, Users};
+use text;
+
+/// The longest request allowed by the spec: a 1024 byte URL plus CRLF.
+const MAX_REQUEST_LEN: usize = 1026;
+
+/// How many search results to show.
+const MAX_RESULTS: usize = 20;
+
+/// How many requests are answered at once. Connections beyond this are
+/// closed straight away.
+const MAX_CONNECTIONS: usize = 32;
+
+/// How long a client has to finish the TLS handshake and send its request.
+const READ_TIMEOUT: u64 = 10;
+
+/// A Gemini response header and body.
+struct Response {
+    status: u8,
+    meta: String,
+    body: String,
+}
+
+impl Response {
+    fn gemtext(body: String) -> Response {
+        Response { status: 20, meta: "text/gemini; charset=utf-8".to_string(), body: body }
+    }
+
+    fn input(prompt: &str) -> Response {
+        Response { status: 10, meta: prompt.to_string(), body: String::new() }
+    }
+
+    fn redirect(url: String) -> Response {
+        Response { status: 30, meta: url, body: String::new() }
+    }
+
+    fn not_found() -> Response {
+        Response { status: 51, meta: "Not found".to_string(), body: String::new() }
+    }
+
+    fn bad_request(reason: &str) -> Response {
+        Response { status: 59, meta: reason.to_string(), body: String::new() }
+    }
+}
+
+/// Listens for Gemini requests on the given address, in a background thread.
+///
+/// The TLS certificate and key are read from a PKCS #12 archive.
+/// `profile_url` gives the address of a user's page on the web site.
+pub fn start(
+    addr: &str, identity_path: &Path, password: &str, users: Arc<RwLock<Users>>,
+    profile_url: fn(&str) -> String) -> io::Result<()>
+{
+    let identity = Identity::from_pkcs12(&fs::read(identity_path)?, password)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let acceptor = TlsAcceptor::new(identity)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let acceptor = Arc::new(acceptor);
+    let listener = TcpListener::bind(addr)?;
+    info!("gemini server listening on {}", addr);
+    let connections = Connections::new(MAX_CONNECTIONS);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let slot = match connections.take() {
+                        Some(slot) => slot,
+                        None => {
+                            warn!("gemini connection dropped: too many connections");
+                            continue;
+                        },
+                    };
+                    let acceptor = acceptor.clone();
+                    let users = users.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle(stream, &acceptor, &users, profile_url) {
+                            warn!("gemini error: {}", e);
+                        }
+                        drop(slot);
+                    });
+                },
+                Err(e) => error!("gemini connection failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle(
+    stream: TcpStream, acceptor: &TlsAcceptor, users: &RwLock<Users>,
+    profile_url: fn(&str) -> String) -> io::Result<()>
+{
+    let stream = Deadline::new(stream, Duration::from_secs(READ_TIMEOUT))?;
+    let mut stream = acceptor.accept(stream)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    exchange(&mut stream, users, profile_url)
+}
+
+/// Reads a request from an established connection, and writes the response.
+fn exchange<S: Read + Write>(
+    stream: &mut S, users: &RwLock<Users>, profile_url: fn(&str) -> String) -> io::Result<()>
+{
+    let mut line = String::new();
+    BufReader::new(stream.by_ref().take(MAX_REQUEST_LEN as u64)).read_line(&mut line)?;
+    let response = if line.ends_with("\r\n") {
+        respond(line.trim_right(), &users.read().unwrap(), profile_url)
+    } else {
+        Response::bad_request("Request too long")
+    };
+    write!(stream, "{} {}\r\n", response.status, response.meta)?;
+    stream.write_all(response.body.as_bytes())?;
+    stream.flush()
+}
+
+fn respond(request: &str, users: &Users, profile_url: fn(&str) -> String) -> Response {
+    let url = match Url::parse(request) {
+        Ok(ref url) if url.scheme() == "gemini" => url.clone(),
+        _ => return Response::bad_request("Not a Gemini URL"),
+    };
+    let query = url.query()
+        .map(|query| percent_decode(query.as_bytes()).decode_utf8_lossy().into_owned());
+    let segments: Vec<&str> = url.path_segments()
+        .map_or_else(Vec::new, |segments| segments.filter(|s| !s.is_empty()).collect());
+    match (&segments[..], query) {
+        (&[], _) => Response::gemtext(home(users)),
+        (&["user", id], _) => match users.get(id) {
+            Some(Ok(user)) => Response::gemtext(user_page(id, user, profile_url)),
+            Some(Err(error)) => Response::gemtext(format!(
+                "# {}\n\nThe user {} exists, but their entry could not be parsed.\n\n> {}\n",
+                id, id, inline(error))),
+            None => Response::not_found(),
+        },
+        (&["search"], None) => Response::input("Search for Rustaceans"),
+        (&["search"], Some(query)) => Response::gemtext(search_results(&query, users)),
+        (&["random"], _) => match users.random_id() {
+            Some(id) => Response::redirect(format!("/user/{}", id)),
+            None => Response::not_found(),
+        },
+        _ => Response::not_found(),
+    }
+}
+
+fn home(users: &Users) -> String {
+    format!(
+        concat!(
+            "# 🦀 Karkinos\n\n",
+            "A directory of {} people interested in the Rust programming language.\n\n",
+            "=> /search Search\n",
+            "=> /random View a random Rustacean\n"),
+        users.len())
+}
+
+fn user_page(id: &str, user: &User, profile_url: fn(&str) -> String) -> String {
+    let mut out = String::new();
+    match user.name {
+        Some(ref name) => out.push_str(&format!("# {} ({})\n\n", inline(name), id)),
+        None => out.push_str(&format!("# {}\n\n", id)),
+    }
+    for (label, text, link) in text::details(id, user) {
+        match link {
+            Some(link) => out.push_str(&format!(
+                "=> {} {}: {}\n", inline(&link), inline(&label), inline(&text))),
+            None => out.push_str(&text_line(&format!("{}: {}", label, text))),
+        }
+    }
+    if let Some(ref notes) = user.notes {
+        out.push('\n');
+        out.push_str(&markdown_to_gemtext(notes));
+    }
+    out.push_str(&format!("\n=> {} View on the web\n", profile_url(id)));
+    out
+}
+
+fn search_results(query: &str, users: &Users) -> String {
+    let mut out = format!("# Search results for “{}”\n\n", inline(query));
+    let (results, correction) = users.search(query);
+    if let Some(correction) = correction {
+        out.push_str(&format!("Showing results for “{}”\n\n", correction));
+    }
+    if results.is_empty() {
+        out.push_str("No results found.\n");
+    }
+    for (id, _) in results.into_iter().take(MAX_RESULTS) {
+        match users.get(&id) {
+            Some(Ok(&User { name: Some(ref name), .. })) =>
+                out.push_str(&format!("=> /user/{} {} ({})\n", id, inline(name), id)),
+            _ => out.push_str(&format!("=> /user/{} {}\n", id, id)),
+        }
+    }
+    out.push_str("\n=> /search Search again\n");
+    out
+}
+
+/// Converts Markdown to gemtext, which has no inline formatting. Links are
+/// moved to their own lines after the block they appear in.
+fn markdown_to_gemtext(markdown: &str) -> String {
+    let mut out = String::new();
+    let mut line = String::new();
+    let mut links: Vec<(String, String)> = Vec::new();
+    // Where the text of the current link starts in `line`
+    let mut link_start = 0;
+    let mut prefix = "";
+    let mut quoted = false;
+    let mut in_code_block = false;
+    fn flush(out: &mut String, line: &mut String, links: &mut Vec<(String, String)>, prefix: &str,
+             quoted: bool) {
+        if !line.trim().is_empty() {
+            match (quoted, prefix) {
+                (true, _) => out.push_str(&format!("> {}\n", inline(line))),
+                (false, "") => out.push_str(&text_line(line)),
+                (false, prefix) => out.push_str(&format!("{}{}\n", prefix, inline(line))),
+            }
+        }
+        line.clear();
+        for (url, text) in links.drain(..) {
+            out.push_str(&format!("=> {} {}\n", inline(&url), inline(&text)));
+        }
+    }
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::Header(level)) => {
+                // The user's name is the top level heading
+                prefix = match level { 1 => "## ", _ => "### " };
+            },
+            Event::Start(Tag::Item) => prefix = "* ",
+            Event::Start(Tag::BlockQuote) => quoted = true,
+            Event::End(Tag::BlockQuote) => quoted = false,
+            Event::Start(Tag::CodeBlock(_)) => {
+                flush(&mut out, &mut line, &mut links, prefix, quoted);
+                out.push_str("```\n");
+                in_code_block = true;
+            },
+            Event::End(Tag::CodeBlock(_)) => {
+                if !out.ends_with('\n') {
+                    out.push('\n');
+                }
+                out.push_str("```\n\n");
+                in_code_block = false;
+            },
+            Event::Start(Tag::Link(..)) | Event::Start(Tag::Image(..)) => link_start = line.len(),
+            Event::End(Tag::Link(url, _)) | Event::End(Tag::Image(url, _)) => {
+                let mut text = line[link_start..].trim().to_string();
+                if text.is_empty() {
+                    text = "Link".to_string();
+                }
+                links.push((url.into_owned(), text));
+            },
+            Event::End(Tag::Header(_)) | Event::End(Tag::Item) => {
+                flush(&mut out, &mut line, &mut links, prefix, quoted);
+                prefix = "";
+            },
+            Event::End(Tag::Paragraph) | Event::End(Tag::Rule) => {
+                flush(&mut out, &mut line, &mut links, prefix, quoted);
+                out.push('\n');
+            },
+            Event::End(Tag::List(_)) => out.push('\n'),
+            Event::Text(text) => if in_code_block {
+                // Don't let the code end the block early
+                if (out.is_empty() || out.ends_with('\n')) && text.starts_with("```") {
+                    out.push(' ');
+                }
+                out.push_str(&text.replace("\n```", "\n ```"));
+            } else {
+                line.push_str(&text);
+            },
+            Event::SoftBreak => line.push(' '),
+            Event::HardBreak => flush(&mut out, &mut line, &mut links, prefix, quoted),
+            _ => {},
+        }
+    }
+    flush(&mut out, &mut line, &mut links, prefix, quoted);
+    out
+}
+
+/// Joins text onto one line, so that it can't start a line of its own.
+fn inline(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Renders text as a plain text line, with a space in front if it would
+/// otherwise be taken as a link, heading, list item, quote or preformatting.
+fn text_line(text: &str) -> String {
+    let text = inline(text);
+    if ["=>", "#", "* ", ">", "```"].iter().any(|marker| text.starts_with(marker)) {
+        format!(" {}\n", text)
+    } else {
+        format!("{}\n", text)
+    }
+}
+
+#[test]
+fn gemtext() {
+    let gemtext = markdown_to_gemtext(
+        "# Hello\n\nI work on [Rust](https://www.rust-lang.org) and *crabs*.\n\n- one\n- two\n");
+    assert_eq!(gemtext, concat!(
+        "## Hello\n",
+        "I work on Rust and crabs.\n",
+        "=> https://www.rust-lang.org Rust\n",
+        "\n",
+        "* one\n",
+        "* two\n",
+        "\n"));
+}
+
+#[test]
+fn gemtext_lines() {
+    let gemtext = markdown_to_gemtext(
+        "=> gemini://evil.example Click\n\n\\# Hi\n\n    ```\n    code\n");
+    assert_eq!(gemtext, concat!(
+        " => gemini://evil.example Click\n",
+        "\n",
+        " # Hi\n",
+        "\n",
+        "```\n",
+        " ```\n",
+        "code\n",
+        "```\n\n"));
+    assert_eq!(text_line("Desk: Level 3\n=> x"), "Desk: Level 3 => x\n");
+}
+
+#[test]
+fn round_trip() {
+    use std::io::Cursor;
+
+    /// A connection with the request already sent.
+    struct Connection {
+        request: Cursor<Vec<u8>>,
+        response: Vec<u8>,
+    }
+
+    impl Read for Connection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.request.read(buf)
+        }
+    }
+
+    impl Write for Connection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.response.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn profile_url(id: &str) -> String {
+        format!("https://karkinos.example/user/{}", id)
+    }
+
+    let users = RwLock::new(Users::from_json(&[
+        ("ferris", r#"{ "name": "Ferris", "notes": "Likes [the sea](https://sea.example)." }"#),
+    ]));
+    let request = |request: &str| {
+        let mut connection = Connection {
+            request: Cursor::new(request.as_bytes().to_vec()),
+            response: Vec::new(),
+        };
+        exchange(&mut connection, &users, profile_url).unwrap();
+        String::from_utf8(connection.response).unwrap()
+    };
+
+    let page = request("gemini://karkinos.example/user/ferris\r\n");
+    assert!(page.starts_with("20 text/gemini; charset=utf-8\r\n# Ferris (ferris)\n"));
+    assert!(page.contains("=> https://sea.example the sea\n"));
+    assert!(page.ends_with("=> https://karkinos.example/user/ferris View on the web\n"));
+    assert_eq!(request("gemini://karkinos.example/user/corro\r\n"), "51 Not found\r\n");
+    assert_eq!(request("gemini://karkinos.example/search\r\n"),
+               "10 Search for Rustaceans\r\n");
+    assert!(request("gemini://karkinos.example/search?fer%72is\r\n")
+        .contains("=> /user/ferris Ferris (ferris)\n"));
+    assert_eq!(request("https://karkinos.example/\r\n"), "59 Not a Gemini URL\r\n");
+    // A request has to fit in 1024 bytes, and end with CRLF
+    let long = format!("gemini://karkinos.example/{}\r\n", "x".repeat(1024));
+    assert_eq!(request(&long), "59 Request too long\r\n");
+    assert_eq!(request("gemini://karkinos.example/"), "59 Request too long\r\n");
+}
@@ -0,0 +1,121 @@
+//! How IRC channels overlap, for working out which groups to reach when
+//! organising something.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::f64::consts::PI;
+
+/// Channels, and how many people each pair of them have in common.
+#[derive(Debug)]
+pub struct ChannelGraph {
+    /// Each channel's name and how many people are in it.
+    pub channels: Vec<(String, usize)>,
+    /// Pairs of channels, as indices into `channels`, and how many people
+    /// are in both. Pairs with nobody in common are left out.
+    pub overlaps: Vec<(usize, usize, usize)>,
+}
+
+impl ChannelGraph {
+    /// Builds the graph from the members of each channel, as returned by
+    /// `Users::channels`.
+    pub fn new<S: AsRef<str>>(channels: &BTreeMap<String, Vec<S>>) -> ChannelGraph {
+        let mut memberships: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for (i, members) in channels.values().enumerate() {
+            for id in members {
+                memberships.entry(id.as_ref()).or_insert_with(Vec::new).push(i);
+            }
+        }
+        let mut shared = BTreeMap::new();
+        for joined in memberships.values() {
+            for (n, &a) in joined.iter().enumerate() {
+                for &b in &joined[n + 1..] {
+                    *shared.entry((a, b)).or_insert(0) += 1;
+                }
+            }
+        }
+        ChannelGraph {
+            channels: channels.iter()
+                .map(|(name, members)| (name.clone(), members.len()))
+                .collect(),
+            overlaps: shared.into_iter().map(|((a, b), count)| (a, b, count)).collect(),
+        }
+    }
+
+    /// Returns the part of the graph around the given channels: those
+    /// channels, every channel that shares someone with them, and the
+    /// overlaps between the two.
+    pub fn neighbourhood(&self, around: &[String]) -> ChannelGraph {
+        let centre: BTreeSet<usize> = self.channels.iter()
+            .enumerate()
+            .filter(|&(_, &(ref name, _))| around.iter().any(|c| c.to_lowercase() == *name))
+            .map(|(i, _)| i)
+            .collect();
+        let overlaps: Vec<_> = self.overlaps.iter()
+            .filter(|&&(a, b, _)| centre.contains(&a) || centre.contains(&b))
+            .cloned()
+            .collect();
+        let kept: BTreeSet<usize> = centre.iter().cloned()
+            .chain(overlaps.iter().flat_map(|&(a, b, _)| vec![a, b]))
+            .collect();
+        let positions: BTreeMap<usize, usize> = kept.iter()
+            .enumerate()
+            .map(|(new, &old)| (old, new))
+            .collect();
+        ChannelGraph {
+            channels: kept.iter().map(|&i| self.channels[i].clone()).collect(),
+            overlaps: overlaps.into_iter()
+                .map(|(a, b, count)| (positions[&a], positions[&b], count))
+                .collect(),
+        }
+    }
+
+    /// Returns the channels that overlap with the named one, most shared
+    /// members first.
+    pub fn overlapping(&self, name: &str) -> Vec<(&str, usize)> {
+        let i = match self.channels.iter().position(|&(ref c, _)| c == name) {
+            Some(i) => i,
+            None => return vec![],
+        };
+        let mut overlapping: Vec<_> = self.overlaps.iter()
+            .filter_map(|&(a, b, count)| {
+                if a == i {
+                    Some((&self.channels[b].0[..], count))
+                } else if b == i {
+                    Some((&self.channels[a].0[..], count))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        overlapping.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        overlapping
+    }
+
+    /// Places the channels evenly around a circle of the given radius,
+    /// centred on the origin, starting at the top and going clockwise.
+    pub fn circle(&self, radius: f64) -> Vec<(f64, f64)> {
+        let n = self.channels.len() as f64;
+        (0..self.channels.len())
+            .map(|i| {
+                let angle = 2.0 * PI * i as f64 / n - PI / 2.0;
+                (radius * angle.cos(), radius * angle.sin())
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn overlaps() {
+    let mut channels = BTreeMap::new();
+    channels.insert("rust".to_string(), vec!["a", "b", "c"]);
+    channels.insert("rust-beginners".to_string(), vec!["a", "b"]);
+    channels.insert("rust-embedded".to_string(), vec!["c"]);
+    channels.insert("servo".to_string(), vec!["d"]);
+    let graph = ChannelGraph::new(&channels);
+    assert_eq!(graph.overlaps, vec![(0, 1, 2), (0, 2, 1)]);
+    assert_eq!(graph.overlapping("rust"), vec![("rust-beginners", 2), ("rust-embedded", 1)]);
+
+    let around = graph.neighbourhood(&["Rust-Beginners".to_string()]);
+    let names: Vec<&str> = around.channels.iter().map(|&(ref name, _)| &name[..]).collect();
+    assert_eq!(names, vec!["rust", "rust-beginners"]);
+    assert_eq!(around.overlaps, vec![(0, 1, 2)]);
+}
@@ -0,0 +1,247 @@
+//! A GraphQL view of the directory, for dashboards that only want a few
+//! fields out of each entry.
+
+use juniper::{self, FieldResult};
+use std::sync::{Arc, RwLock};
+
+use models::{User, Users};
+
+/// The most search results that can be asked for at once.
+const MAX_PAGE_SIZE: i32 = 100;
+
+pub struct Context {
+    pub users: Arc<RwLock<Users>>,
+}
+
+impl juniper::Context for Context {}
+
+pub struct Query;
+
+graphql_object!(Query: Context |&self| {
+    field user(&executor, id: String) -> Option<UserObject> as "Look up a user by GitHub id." {
+        let users = executor.context().users.read().unwrap();
+        users.get(&id).and_then(Result::ok).map(|user| UserObject::new(&id, user))
+    }
+
+    field search(
+        &executor,
+        query: String,
+        first = 20: i32 as "How many results to return, up to 100.",
+        after: Option<String> as "The cursor of the last result on the previous page."
+    ) -> FieldResult<SearchConnection> as "Search the directory, most relevant first." {
+        let start = match after {
+            Some(cursor) => cursor.parse::<usize>().ok()
+                .and_then(|i| i.checked_add(1))
+                .ok_or("Invalid cursor")?,
+            None => 0,
+        };
+        let first = first.max(0).min(MAX_PAGE_SIZE) as usize;
+        let users = executor.context().users.read().unwrap();
+        let (results, _) = users.search(&query);
+        let total_count = results.len();
+        let edges: Vec<SearchEdge> = results.into_iter()
+            .enumerate()
+            .skip(start)
+            .take(first)
+            .filter_map(|(i, (id, weight))| {
+                let user = users.get(&id)?.ok()?;
+                Some(SearchEdge {
+                    cursor: i.to_string(),
+                    weight: weight,
+                    node: UserObject::new(&id, user),
+                })
+            })
+            .collect();
+        Ok(SearchConnection {
+            has_next_page: start.saturating_add(first) < total_count,
+            end_cursor: edges.last().map(|edge| edge.cursor.clone()),
+            total_count: total_count,
+            edges: edges,
+        })
+    }
+
+    field channels(&executor) -> Vec<Channel> as "Every IRC channel that someone has listed." {
+        let users = executor.context().users.read().unwrap();
+        let channels = users.channels().iter()
+            .map(|(name, members)| Channel::new(&users, name.clone(), members))
+            .collect();
+        channels
+    }
+
+    field channel(&executor, name: String) -> Option<Channel> {
+        let name = name.trim_left_matches('#').to_lowercase();
+        let users = executor.context().users.read().unwrap();
+        users.channels().get(&name).map(|members| Channel::new(&users, name.clone(), members))
+    }
+
+    field load_errors(&executor) -> Vec<LoadError> as "Entries that could not be parsed." {
+        let users = executor.context().users.read().unwrap();
+        let errors = users.errors()
+            .map(|(id, message)| LoadError { id: id.to_string(), message: message.to_string() })
+            .collect();
+        errors
+    }
+});
+
+/// A copy of an entry, so that it can outlive the lock on `Users`.
+pub struct UserObject {
+    id: String,
+    user: User,
+}
+
+impl UserObject {
+    fn new(id: &str, user: &User) -> UserObject {
+        UserObject { id: id.to_string(), user: user.clone() }
+    }
+}
+
+graphql_object!(UserObject: Context as "User" |&self| {
+    description: "A Rustacean."
+
+    field id() -> &str as "Their GitHub id." { &self.id }
+    field name() -> Option<&str> { self.user.name.as_ref().map(|s| &s[..]) }
+    field irc() -> Option<&str> { self.user.irc.as_ref().map(|s| &s[..]) }
+    field irc_channels() -> Vec<String> { self.user.irc_channels.clone() }
+    field show_avatar() -> bool { self.user.show_avatar }
+    field email() -> Option<&str> { self.user.email.as_ref().map(|s| &s[..]) }
+    field discourse() -> Option<&str> { self.user.discourse.as_ref().map(|s| &s[..]) }
+    field reddit() -> Option<&str> { self.user.reddit.as_ref().map(|s| &s[..]) }
+    field twitter() -> Option<&str> { self.user.twitter.as_ref().map(|s| &s[..]) }
+    field blog() -> Option<&str> { self.user.blog.as_ref().map(|s| &s[..]) }
+    field website() -> Option<&str> { self.user.website.as_ref().map(|s| &s[..]) }
+    field notes() -> Option<&str> as "Free-form notes, in Markdown." {
+        self.user.notes.as_ref().map(|s| &s[..])
+    }
+    field extra(key: String) -> Option<String> as "A field added by a private data layer." {
+        self.user.extra.get(&key).map(|value| match value.as_str() {
+            Some(s) => s.to_string(),
+            None => value.to_string(),
+        })
+    }
+});
+
+pub struct SearchConnection {
+    edges: Vec<SearchEdge>,
+    has_next_page: bool,
+    end_cursor: Option<String>,
+    total_count: usize,
+}
+
+graphql_object!(SearchConnection: Context |&self| {
+    field edges() -> &[SearchEdge] { &self.edges }
+    field page_info() -> PageInfo {
+        PageInfo { has_next_page: self.has_next_page, end_cursor: self.end_cursor.clone() }
+    }
+    field total_count() -> i32 { self.total_count as i32 }
+});
+
+pub struct SearchEdge {
+    cursor: String,
+    weight: u64,
+    node: UserObject,
+}
+
+graphql_object!(SearchEdge: Context |&self| {
+    field cursor() -> &str { &self.cursor }
+    field node() -> &UserObject { &self.node }
+    field weight() -> f64 as "How well the result matched the query." { self.weight as f64 }
+});
+
+pub struct PageInfo {
+    has_next_page: bool,
+    end_cursor: Option<String>,
+}
+
+graphql_object!(PageInfo: Context |&self| {
+    field has_next_page() -> bool { self.has_next_page }
+    field end_cursor() -> Option<&str> { self.end_cursor.as_ref().map(|s| &s[..]) }
+});
+
+/// A channel, holding on to just the ids of its members so that their
+/// entries are only copied if they're asked for.
+pub struct Channel {
+    name: String,
+    members: Vec<String>,
+}
+
+impl Channel {
+    fn new(users: &Users, name: String, members: &[String]) -> Channel {
+        let members = members.iter()
+            .filter(|id| users.get(id).map_or(false, |user| user.is_ok()))
+            .cloned()
+            .collect();
+        Channel { name: name, members: members }
+    }
+}
+
+graphql_object!(Channel: Context |&self| {
+    field name() -> &str as "The channel name, without the leading #." { &self.name }
+    field member_count() -> i32 { self.members.len() as i32 }
+    field members(&executor) -> Vec<UserObject> {
+        let users = executor.context().users.read().unwrap();
+        let members = self.members.iter()
+            .filter_map(|id| {
+                users.get(id).and_then(Result::ok).map(|user| UserObject::new(id, user))
+            })
+            .collect();
+        members
+    }
+});
+
+pub struct LoadError {
+    id: String,
+    message: String,
+}
+
+graphql_object!(LoadError: Context |&self| {
+    field id() -> &str { &self.id }
+    field message() -> &str { &self.message }
+});
+
+#[cfg(test)]
+fn test_context() -> Context {
+    let users = Users::from_json(&[
+        ("ferris", r#"{ "name": "Ferris", "irc_channels": ["rust", "#rust-embedded"] }"#),
+        ("corro", r#"{ "irc_channels": ["Rust"] }"#),
+        ("broken", r#"{ "irc_channels": "rust" }"#),
+    ]);
+    Context { users: Arc::new(RwLock::new(users)) }
+}
+
+#[test]
+fn channel_query() {
+    use juniper::{EmptyMutation, RootNode, Variables};
+    use serde_json::{self, Value};
+
+    let context = test_context();
+    let schema = RootNode::new(Query, EmptyMutation::<Context>::new());
+    let (result, errors) = juniper::execute(
+        r#"{ channel(name: "#rust") { name memberCount members { id name } } }"#,
+        None, &schema, &Variables::new(), &context).unwrap();
+    assert!(errors.is_empty());
+    let expected: Value = serde_json::from_str(r#"{
+        "channel": {
+            "name": "rust",
+            "memberCount": 2,
+            "members": [{ "id": "corro", "name": null }, { "id": "ferris", "name": "Ferris" }]
+        }
+    }"#).unwrap();
+    assert_eq!(serde_json::to_value(&result).unwrap(), expected);
+}
+
+#[test]
+fn search_cursor() {
+    use juniper::{EmptyMutation, RootNode, Variables};
+
+    let context = test_context();
+    let schema = RootNode::new(Query, EmptyMutation::<Context>::new());
+    let search = |after: &str| {
+        let query = format!(
+            r#"{{ search(query: "ferris", after: "{}") {{ totalCount }} }}"#, after);
+        juniper::execute(&query, None, &schema, &Variables::new(), &context).unwrap().1
+    };
+    assert!(search("0").is_empty());
+    // The largest possible cursor has nothing after it
+    assert!(search("18446744073709551614").is_empty());
+    assert_eq!(search("18446744073709551615").len(), 1);
+}
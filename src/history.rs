@@ -0,0 +1,235 @@
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::{self, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::Mutex;
+
+/// A commit that touched someone's entry.
+#[derive(Clone, Debug)]
+pub struct Revision {
+    pub commit: String,
+    pub time: DateTime<Utc>,
+    pub summary: String,
+}
+
+/// The number of changes to keep for the feed.
+const RECENT_CHANGES: usize = 30;
+
+/// The git history of every entry in the data repository.
+#[derive(Debug, Default)]
+pub struct History {
+    repo_dir: PathBuf,
+    /// Revisions for each entry, newest first.
+    revisions: BTreeMap<String, Vec<Revision>>,
+    /// The latest additions and updates across all entries, newest first.
+    recent: Vec<RecentChange>,
+    /// Entries already read by `entry_at`, keyed by commit and id. The
+    /// history is reloaded along with the data, so this never goes stale,
+    /// and can't grow beyond the size of the history itself.
+    entries: Mutex<HashMap<(String, String), Option<Value>>>,
+}
+
+/// An entry that was added or updated in a single commit.
+#[derive(Debug)]
+pub struct RecentChange {
+    pub id: String,
+    pub revision: Revision,
+    /// Whether this commit created the entry.
+    pub added: bool,
+    pub fields: Vec<FieldChange>,
+}
+
+impl History {
+    /// Reads the history of the repository in a single pass over `git log`.
+    pub fn load<P: AsRef<Path>>(repo_dir: P) -> io::Result<History> {
+        let repo_dir = repo_dir.as_ref();
+        let Output { status, stdout, .. } = Command::new("git")
+            .arg("log").arg("--no-renames").arg("--name-only")
+            // Separate commits with RS, and fields with US
+            .arg("--format=%x1e%H%x1f%ct%x1f%s")
+            .arg("--").arg("data")
+            .current_dir(repo_dir)
+            .output()?;
+        if !status.success() {
+            return Err(io::Error::new(io::ErrorKind::Other, "failed to read git history"));
+        }
+        let mut revisions = BTreeMap::new();
+        for chunk in String::from_utf8_lossy(&stdout).split('\x1e') {
+            let mut lines = chunk.lines();
+            let header: Vec<&str> = match lines.next() {
+                Some(header) => header.splitn(3, '\x1f').collect(),
+                None => continue,
+            };
+            if header.len() < 3 {
+                continue;
+            }
+            let time = match header[1].parse().ok()
+                .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+            {
+                Some(time) => time,
+                None => {
+                    warn!("skipping commit {} with bad time {:?}", header[0], header[1]);
+                    continue;
+                },
+            };
+            let revision = Revision {
+                commit: header[0].to_string(),
+                time: time,
+                summary: header[2].to_string(),
+            };
+            for path in lines {
+                if let Some(id) = entry_id(path) {
+                    revisions.entry(id.to_string()).or_insert_with(Vec::new).push(revision.clone());
+                }
+            }
+        }
+        info!("loaded history for {} entries", revisions.len());
+        let mut history = History {
+            repo_dir: repo_dir.to_path_buf(),
+            revisions: revisions,
+            recent: Vec::new(),
+            entries: Mutex::new(HashMap::new()),
+        };
+        history.recent = history.find_recent_changes()?;
+        Ok(history)
+    }
+
+    /// Works out what changed in the latest commits, skipping over entries
+    /// that were deleted.
+    fn find_recent_changes(&self) -> io::Result<Vec<RecentChange>> {
+        let mut candidates: Vec<(&str, &Revision)> = self.revisions.iter()
+            .flat_map(|(id, revisions)| revisions.iter().map(move |revision| (&id[..], revision)))
+            .collect();
+        candidates.sort_by(|&(_, a), &(_, b)| b.time.cmp(&a.time));
+        let mut recent = Vec::new();
+        for (id, revision) in candidates {
+            if recent.len() >= RECENT_CHANGES {
+                break;
+            }
+            let after = self.entry_at(id, &revision.commit)?;
+            if after.is_none() {
+                continue;
+            }
+            let before = self.entry_at(id, &format!("{}^", revision.commit))?;
+            recent.push(RecentChange {
+                id: id.to_string(),
+                revision: revision.clone(),
+                added: before.is_none(),
+                fields: diff(before.as_ref(), after.as_ref()),
+            });
+        }
+        Ok(recent)
+    }
+
+    /// Returns the latest additions and updates, newest first.
+    pub fn recent_changes(&self) -> &[RecentChange] {
+        &self.recent
+    }
+
+    /// Returns the revisions of the given entry, newest first.
+    pub fn revisions(&self, id: &str) -> &[Revision] {
+        self.revisions.get(id).map(|revisions| &revisions[..]).unwrap_or(&[])
+    }
+
+    /// Returns the contents of the given entry as of a commit, or `None` if it
+    /// did not exist or could not be parsed.
+    pub fn entry_at(&self, id: &str, commit: &str) -> io::Result<Option<Value>> {
+        let key = (commit.to_string(), id.to_string());
+        if let Some(entry) = self.entries.lock().unwrap().get(&key) {
+            return Ok(entry.clone());
+        }
+        let Output { status, stdout, .. } = Command::new("git")
+            .arg("show").arg(format!("{}:data/{}.json", commit, id))
+            .current_dir(&self.repo_dir)
+            .output()?;
+        let entry = if status.success() { serde_json::from_slice(&stdout).ok() } else { None };
+        self.entries.lock().unwrap().insert(key, entry.clone());
+        Ok(entry)
+    }
+
+    /// Returns every revision of the given entry, newest first, along with the
+    /// fields that it changed.
+    pub fn changes(&self, id: &str) -> io::Result<Vec<(&Revision, Vec<FieldChange>)>> {
+        let revisions = self.revisions(id);
+        let mut entries = Vec::with_capacity(revisions.len());
+        for revision in revisions {
+            entries.push(self.entry_at(id, &revision.commit)?);
+        }
+        Ok(revisions.iter().enumerate().map(|(i, revision)| {
+            // The entry didn't change between this revision and the previous
+            // one, so the previous revision tells us what it looked like before
+            let before = entries.get(i + 1).and_then(|entry| entry.as_ref());
+            (revision, diff(before, entries[i].as_ref()))
+        }).collect())
+    }
+}
+
+fn entry_id(path: &str) -> Option<&str> {
+    let path = path.trim();
+    if path.starts_with("data/") && path.ends_with(".json") && !path[5..].contains('/') {
+        Some(&path[5..path.len() - 5])
+    } else {
+        None
+    }
+}
+
+/// A field that differs between two versions of an entry.
+#[derive(Debug, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// Compares two versions of an entry field by field.
+pub fn diff(old: Option<&Value>, new: Option<&Value>) -> Vec<FieldChange> {
+    let old = old.and_then(Value::as_object);
+    let new = new.and_then(Value::as_object);
+    let mut fields = BTreeSet::new();
+    fields.extend(old.into_iter().flat_map(|o| o.keys()));
+    fields.extend(new.into_iter().flat_map(|o| o.keys()));
+    fields.into_iter().filter_map(|field| {
+        let before = old.and_then(|o| o.get(field)).and_then(field_to_string);
+        let after = new.and_then(|o| o.get(field)).and_then(field_to_string);
+        if before == after {
+            None
+        } else {
+            Some(FieldChange { field: field.clone(), old: before, new: after })
+        }
+    }).collect()
+}
+
+fn field_to_string(value: &Value) -> Option<String> {
+    match *value {
+        Value::Null => None,
+        Value::String(ref s) if s.trim().is_empty() => None,
+        Value::String(ref s) => Some(s.clone()),
+        Value::Array(ref items) if items.is_empty() => None,
+        Value::Array(ref items) => Some(items.iter()
+            .map(|item| item.as_str().map(str::to_string).unwrap_or_else(|| item.to_string()))
+            .collect::<Vec<_>>()
+            .join(", ")),
+        ref value => Some(value.to_string()),
+    }
+}
+
+#[test]
+fn field_diff() {
+    let old: Value = serde_json::from_str(r#"
+        { "name": "Bors", "irc": "bors", "email": "", "irc_channels": ["rust"] }
+    "#).unwrap();
+    let new: Value = serde_json::from_str(r#"
+        { "name": "Bors", "irc_channels": ["rust", "rust-bots"], "twitter": "bors" }
+    "#).unwrap();
+    assert_eq!(diff(Some(&old), Some(&new)), vec![
+        FieldChange { field: "irc".to_string(), old: Some("bors".to_string()), new: None },
+        FieldChange {
+            field: "irc_channels".to_string(),
+            old: Some("rust".to_string()),
+            new: Some("rust, rust-bots".to_string()),
+        },
+        FieldChange { field: "twitter".to_string(), old: None, new: Some("bors".to_string()) },
+    ]);
+}
@@ -0,0 +1,106 @@
+//! Limits for the servers that speak their own protocols over TCP (finger
+//! and Gemini), so that clients can't tie them up by connecting too often or
+//! by sending their requests slowly.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Counts the connections being handled, up to a limit.
+pub struct Connections {
+    open: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl Connections {
+    pub fn new(max: usize) -> Connections {
+        Connections { open: Arc::new(AtomicUsize::new(0)), max: max }
+    }
+
+    /// Makes room for another connection, or returns `None` if there are too
+    /// many already.
+    pub fn take(&self) -> Option<Slot> {
+        if self.open.fetch_add(1, Ordering::SeqCst) < self.max {
+            Some(Slot(self.open.clone()))
+        } else {
+            self.open.fetch_sub(1, Ordering::SeqCst);
+            None
+        }
+    }
+}
+
+/// A place for one connection, given back when dropped.
+pub struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A connection that can only be read from until a fixed point in time, so
+/// that a client can't hold on to it by sending one byte at a time.
+#[derive(Debug)]
+pub struct Deadline {
+    stream: TcpStream,
+    until: Instant,
+}
+
+impl Deadline {
+    /// Gives the client `timeout` from now to send everything it's going to.
+    /// Writes time out after the same amount of time, but each on its own.
+    pub fn new(stream: TcpStream, timeout: Duration) -> io::Result<Deadline> {
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Deadline { stream: stream, until: Instant::now() + timeout })
+    }
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let now = Instant::now();
+        if now >= self.until {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request took too long"));
+        }
+        self.stream.set_read_timeout(Some(self.until - now))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Deadline {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[test]
+fn connection_limit() {
+    let connections = Connections::new(2);
+    let slots = vec![connections.take().unwrap(), connections.take().unwrap()];
+    assert!(connections.take().is_none());
+    drop(slots);
+    assert_eq!(connections.open.load(Ordering::SeqCst), 0);
+    assert!(connections.take().is_some());
+}
+
+#[test]
+fn deadline() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let mut deadline = Deadline::new(stream, Duration::from_millis(200)).unwrap();
+    // The client sends a byte, then nothing more
+    (&client).write_all(b"x").unwrap();
+    let start = Instant::now();
+    let mut buf = Vec::new();
+    assert!(deadline.read_to_end(&mut buf).is_err());
+    assert_eq!(buf, b"x");
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
@@ -4,21 +4,33 @@
 extern crate ammonia;
 extern crate bk_tree;
 extern crate caseless;
+extern crate chrono;
 extern crate env_logger;
+extern crate hyper;
+extern crate hyper_native_tls;
+extern crate image;
+#[macro_use]
 extern crate iron;
 #[macro_use]
+extern crate juniper;
+extern crate juniper_iron;
+#[macro_use]
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
 extern crate logger;
 extern crate maud;
+extern crate md5;
+extern crate native_tls;
 extern crate notify;
 extern crate persistent;
 extern crate pulldown_cmark;
+extern crate qrcode;
 extern crate radix_trie;
 extern crate rand;
 #[macro_use]
 extern crate router;
+extern crate rusttype;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -27,30 +39,66 @@ extern crate staticfile;
 extern crate unicode_normalization;
 extern crate unicode_segmentation;
 extern crate urlencoded;
+extern crate xml;
 
+use export::Format;
+use iron::headers::{CacheControl, CacheDirective, ETag, EntityTag, IfNoneMatch};
+use iron::mime::Mime;
 use iron::modifiers::Redirect;
 use iron::prelude::*;
 use iron::status;
 use iron::typemap::Key;
+use iron::Handler;
+use juniper_iron::{GraphQLHandler, GraphiQLHandler};
 use logger::Logger;
 use notify::{RecursiveMode, Watcher};
 use router::Router;
-use persistent::State;
+use persistent::{Read, State};
 use staticfile::Static;
 use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::{Arc, RwLock};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
+use text::Flavor;
 use urlencoded::UrlEncodedQuery;
 
+mod api;
+mod avatar;
+mod card;
+mod carddav;
+mod export;
+mod feed;
+mod finger;
+mod gate;
+mod gemini;
+mod graph;
+mod graphql;
+mod history;
+mod limit;
+mod mention;
 mod models;
+mod oembed;
+mod qr;
 mod search;
+mod similar;
+mod site;
+mod text;
 mod update;
+mod util;
+mod vcard;
 mod views;
+mod webfinger;
 
-use models::Users;
-use update::Updater;
+use avatar::Avatars;
+use card::Cards;
+use gate::ReloadGate;
+use history::History;
+use models::{Layer, SortOrder, Users};
+use update::{TrustPolicy, Updater};
 
 lazy_static! {
     static ref IS_PRODUCTION: bool = {
@@ -64,12 +112,40 @@ lazy_static! {
         }
         p
     };
+
+    /// Where the web site lives, for linking to it from other protocols.
+    static ref BASE_URL: String = if *IS_PRODUCTION {
+        "https://karkinos.lambda.xyz".to_string()
+    } else {
+        "http://localhost:8344".to_string()
+    };
+
+    /// The token that unlocks the admin pages, if any.
+    static ref ADMIN_TOKEN: Option<String> = env::var("KARKINOS_ADMIN_TOKEN").ok()
+        .and_then(|token| if token.is_empty() { None } else { Some(token) });
 }
 
 #[derive(Copy, Clone)]
 struct UsersKey;
 impl Key for UsersKey { type Value = Users; }
 
+#[derive(Copy, Clone)]
+struct HistoryKey;
+impl Key for HistoryKey { type Value = History; }
+
+#[derive(Copy, Clone)]
+struct UpdaterKey;
+impl Key for UpdaterKey { type Value = Updater; }
+
+#[derive(Copy, Clone)]
+struct AvatarsKey;
+impl Key for AvatarsKey { type Value = Avatars; }
+
+/// `None` if the fonts couldn't be loaded.
+#[derive(Copy, Clone)]
+struct CardsKey;
+impl Key for CardsKey { type Value = Option<Cards>; }
+
 fn main() {
     // Initialize the logger
     env_logger::init();
@@ -81,15 +157,75 @@ fn main() {
         .to_path_buf();
     info!("using root directory: {}", root_dir.display());
 
+    // Static files are read from the working directory, unless told otherwise
+    let site_root = env::var_os("KARKINOS_SITE_ROOT")
+        .map_or_else(|| PathBuf::from("."), PathBuf::from);
+
+    // Run a subcommand instead of the server, if asked to
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(|arg| &arg[..]) {
+        None => {},
+        Some("build") => {
+            if let Err(e) = build_command(&root_dir, &site_root, &args[1..]) {
+                eprintln!("karkinos build: {}", e);
+                process::exit(1);
+            }
+            return;
+        },
+        Some("export") => {
+            if let Err(e) = export_command(&root_dir, &args[1..]) {
+                eprintln!("karkinos export: {}", e);
+                process::exit(1);
+            }
+            return;
+        },
+        Some(command) => {
+            eprintln!("karkinos: unknown command: {}", command);
+            process::exit(2);
+        },
+    }
+
     // Start the updater thingy
-    let updater = Updater::start(&root_dir).unwrap();
+    let updater = Arc::new(Updater::start(&root_dir, TrustPolicy::from_env()).unwrap());
 
     let mut router = Router::new();
     router.get("/", home, "home");
     router.get("/user/:id", user, "user");
+    router.get("/user/:id/history", user_history, "user_history");
+    router.get("/user/:id/neighbourhood", user_neighbourhood, "user_neighbourhood");
+    router.get("/user/:id/qr.svg", user_qr, "user_qr");
+    router.get("/user/:id/card.png", user_card, "user_card");
+    router.get("/user/:id/badge.svg", user_badge, "user_badge");
+    router.get("/embed/user/:id", embed_user, "embed_user");
+    router.get("/oembed", oembed, "oembed");
+    router.get("/avatar/:id", avatar, "avatar");
     router.get("/search", search, "search");
-    router.get("/static/:path", Static::new(".").cache(Duration::from_secs(60 * 60)), "static");
+    router.get("/search.txt", search, "search_txt");
+    router.get("/search.md", search, "search_md");
+    router.get("/vcards", vcards, "vcards");
+    let static_files = Static::new(&site_root).cache(Duration::from_secs(60 * 60));
+    router.get("/static/:path", static_files, "static");
     router.get("/random", random, "random");
+    router.get("/users", users_index, "users");
+    router.get("/channels", channels, "channels");
+    router.get("/channels.svg", channels_svg, "channels_svg");
+    router.get("/channel/:name", channel, "channel");
+    router.get("/feed.atom", feed, "feed");
+    router.get("/api/changes", api_changes, "api_changes");
+    router.get("/graphql", graphql_handler(), "graphql");
+    router.post("/graphql", graphql_handler(), "graphql_post");
+    router.get("/graphiql", GraphiQLHandler::new("/graphql"), "graphiql");
+    router.get("/export.json", exporter(Format::Json), "export_json");
+    router.get("/export.jsonl", exporter(Format::JsonLines), "export_jsonl");
+    router.get("/export.csv", exporter(Format::Csv), "export_csv");
+    router.get("/admin/status", admin_status, "admin_status");
+    router.get("/.well-known/carddav", well_known_carddav, "well_known_carddav");
+    router.get("/.well-known/webfinger", webfinger, "webfinger");
+    // GET has to be routed separately, or the catch-all below would win
+    router.get("/carddav", carddav, "carddav_get_root");
+    router.get("/carddav/*", carddav, "carddav_get");
+    router.any("/carddav", carddav, "carddav_root");
+    router.any("/carddav/*", carddav, "carddav");
     router.get("*", not_found, "not_found");
 
     fn home(r: &mut Request) -> IronResult<Response> {
@@ -98,18 +234,93 @@ fn main() {
     }
 
     fn user(r: &mut Request) -> IronResult<Response> {
+        let route = r.extensions.get::<Router>().unwrap();
+        let (id, extension) = split_extension(route.find("id").unwrap());
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let users = users.read().unwrap();
+        let history = r.extensions.get::<State<HistoryKey>>().unwrap();
+        let history = history.read().unwrap();
+        let flavor = match extension {
+            Some(extension) => Flavor::from_extension(extension),
+            None => Flavor::negotiate(r),
+        };
+        if let Some(flavor) = flavor {
+            let (code, body) = match users.get(id) {
+                Some(Ok(user)) =>
+                    (status::Ok, text::user(&|id: &str| profile_url(r, id), flavor, id, user)),
+                Some(Err(error)) => (status::Ok, text::user_error(flavor, id, error)),
+                None => (status::NotFound, text::user_not_found(flavor, id)),
+            };
+            let mut response = text_response(code, flavor, body);
+            if extension.is_none() {
+                set_vary(&mut response);
+            }
+            return Ok(response);
+        }
+        let mut response = match users.get(id) {
+            Some(Ok(user)) => match extension {
+                None => {
+                    let card = r.extensions.get::<Read<CardsKey>>().unwrap().is_some();
+                    let body = views::user(
+                        r, id, user, &users, history.revisions(id), card, true);
+                    Response::with((status::Ok, body))
+                },
+                Some("vcf") => {
+                    let profile_url = url_for!(r, "user", "id" => id).to_string();
+                    vcard_response(vcard::vcard(id, user, &profile_url))
+                },
+                Some(_) => {
+                    let body = views::not_found(r);
+                    Response::with((status::NotFound, body))
+                },
+            },
+            Some(Err(error)) => {
+                let body = views::user_error(r, id, error);
+                Response::with((status::Ok, body))
+            },
+            None => {
+                let body = views::user_not_found(r, id);
+                Response::with((status::NotFound, body))
+            },
+        };
+        if extension.is_none() {
+            set_vary(&mut response);
+        }
+        Ok(response)
+    }
+
+    fn user_history(r: &mut Request) -> IronResult<Response> {
+        let route = r.extensions.get::<Router>().unwrap();
+        let id = route.find("id").unwrap();
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let users = users.read().unwrap();
+        let history = r.extensions.get::<State<HistoryKey>>().unwrap();
+        let history = history.read().unwrap();
+        if users.get(id).is_none() && history.revisions(id).is_empty() {
+            let body = views::user_not_found(r, id);
+            return Ok(Response::with((status::NotFound, body)));
+        }
+        let changes = itry!(history.changes(id));
+        let user = users.get(id).and_then(Result::ok);
+        let body = views::user_history(r, id, user, &changes);
+        Ok(Response::with((status::Ok, body)))
+    }
+
+    fn user_neighbourhood(r: &mut Request) -> IronResult<Response> {
         let route = r.extensions.get::<Router>().unwrap();
         let id = route.find("id").unwrap();
         let users = r.extensions.get::<State<UsersKey>>().unwrap();
-        match users.read().unwrap().get(id) {
+        let users = users.read().unwrap();
+        match users.get(id) {
             Some(Ok(user)) => {
-                let body = views::user(r, id, user);
+                let graph = users.channel_graph().neighbourhood(&user.irc_channels);
+                let body = views::neighbourhood(r, id, user, &graph);
                 Ok(Response::with((status::Ok, body)))
             },
             Some(Err(error)) => {
                 let body = views::user_error(r, id, error);
                 Ok(Response::with((status::Ok, body)))
-            }
+            },
             None => {
                 let body = views::user_not_found(r, id);
                 Ok(Response::with((status::NotFound, body)))
@@ -117,25 +328,203 @@ fn main() {
         }
     }
 
+    fn user_qr(r: &mut Request) -> IronResult<Response> {
+        let of = query_param(r, "of");
+        let route = r.extensions.get::<Router>().unwrap();
+        let id = route.find("id").unwrap();
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let users = users.read().unwrap();
+        let user = match users.get(id) {
+            Some(user) => user.ok(),
+            None => {
+                let body = views::user_not_found(r, id);
+                return Ok(Response::with((status::NotFound, body)));
+            },
+        };
+        let profile_url = profile_url(r, id);
+        let svg = match (of, user) {
+            (Some(ref of), Some(user)) if of == "vcard" =>
+                // A long vCard might not fit, but the address always will
+                qr::svg(&vcard::vcard(id, user, &profile_url)).or_else(|_| qr::svg(&profile_url)),
+            _ => qr::svg(&profile_url),
+        };
+        let content_type: Mime = "image/svg+xml; charset=utf-8".parse().unwrap();
+        Ok(Response::with((status::Ok, content_type, itry!(svg))))
+    }
+
+    fn user_card(r: &mut Request) -> IronResult<Response> {
+        let route = r.extensions.get::<Router>().unwrap();
+        let id = route.find("id").unwrap();
+        let cards = r.extensions.get::<Read<CardsKey>>().unwrap().clone();
+        let (generation, user) = {
+            let users = r.extensions.get::<State<UsersKey>>().unwrap();
+            let users = users.read().unwrap();
+            (users.generation(), users.get(id).map(|user| user.ok().cloned()))
+        };
+        match (&*cards, user) {
+            // Don't hold the lock while drawing
+            (&Some(ref cards), Some(Some(user))) => {
+                let card = itry!(cards.get(generation, id, &user));
+                let content_type: Mime = "image/png".parse().unwrap();
+                let mut response = Response::with((status::Ok, content_type, (*card).clone()));
+                response.headers.set(CacheControl(vec![CacheDirective::MaxAge(60 * 60)]));
+                Ok(response)
+            },
+            (&None, Some(_)) => {
+                let icon = url_for!(r, "static", "path" => "icon.png");
+                Ok(Response::with((status::Found, Redirect(icon))))
+            },
+            _ => {
+                let body = views::not_found(r);
+                Ok(Response::with((status::NotFound, body)))
+            },
+        }
+    }
+
+    fn user_badge(r: &mut Request) -> IronResult<Response> {
+        let route = r.extensions.get::<Router>().unwrap();
+        let id = route.find("id").unwrap();
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let users = users.read().unwrap();
+        if users.get(id).is_none() {
+            let body = views::not_found(r);
+            return Ok(Response::with((status::NotFound, body)));
+        }
+        let content_type: Mime = "image/svg+xml; charset=utf-8".parse().unwrap();
+        let mut response = Response::with((status::Ok, content_type, views::badge(id)));
+        response.headers.set(CacheControl(vec![CacheDirective::MaxAge(24 * 60 * 60)]));
+        Ok(response)
+    }
+
+    fn embed_user(r: &mut Request) -> IronResult<Response> {
+        let route = r.extensions.get::<Router>().unwrap();
+        let id = route.find("id").unwrap();
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let users = users.read().unwrap();
+        match users.get(id) {
+            Some(Ok(user)) => {
+                let body = views::embed(r, id, user, users.mentions(id));
+                Ok(Response::with((status::Ok, body)))
+            },
+            _ => {
+                let body = views::not_found(r);
+                Ok(Response::with((status::NotFound, body)))
+            },
+        }
+    }
+
+    fn oembed(r: &mut Request) -> IronResult<Response> {
+        let url = query_param(r, "url");
+        let format = query_param(r, "format");
+        let max_width = query_param(r, "maxwidth").and_then(|s| s.parse().ok());
+        let max_height = query_param(r, "maxheight").and_then(|s| s.parse().ok());
+        let url = match url {
+            Some(url) => url,
+            None => return Ok(Response::with((status::BadRequest, "Missing url\n"))),
+        };
+        if format.map_or(false, |format| format != "json") {
+            return Ok(Response::with((status::NotImplemented, "Only JSON is supported\n")));
+        }
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let users = users.read().unwrap();
+        let card = r.extensions.get::<Read<CardsKey>>().unwrap().is_some();
+        match oembed::lookup(r, &users, &url, max_width, max_height, card) {
+            Some(oembed) => {
+                let content_type: Mime = "application/json".parse().unwrap();
+                let body = serde_json::to_string(&oembed).unwrap();
+                Ok(Response::with((status::Ok, content_type, body)))
+            },
+            None => Ok(Response::with((status::NotFound, "No such user\n"))),
+        }
+    }
+
+    fn avatar(r: &mut Request) -> IronResult<Response> {
+        let route = r.extensions.get::<Router>().unwrap();
+        let id = route.find("id").unwrap();
+        let user = {
+            let users = r.extensions.get::<State<UsersKey>>().unwrap();
+            let users = users.read().unwrap();
+            users.get(id).map(|user| user.ok().cloned())
+        };
+        let avatar = match user {
+            // Don't hold the lock while we wait for GitHub
+            Some(Some(user)) => r.extensions.get::<Read<AvatarsKey>>().unwrap().get(id, &user),
+            Some(None) => avatar::identicon(id),
+            None => {
+                let body = views::not_found(r);
+                return Ok(Response::with((status::NotFound, body)));
+            },
+        };
+        let content_type: Mime = avatar.content_type.parse().unwrap();
+        let mut response = Response::with((status::Ok, content_type, avatar.body));
+        response.headers.set(CacheControl(vec![CacheDirective::MaxAge(60 * 60)]));
+        Ok(response)
+    }
+
     fn search(r: &mut Request) -> IronResult<Response> {
         let q: Option<String> = r.get_ref::<UrlEncodedQuery>().ok()
             .and_then(|query| query.get("q"))
             .and_then(|q| q.first().cloned());
+        let extension = r.url.path().last().and_then(|segment| split_extension(segment).1)
+            .map(str::to_string);
+        let flavor = match extension {
+            Some(ref extension) => Flavor::from_extension(extension),
+            None => Flavor::negotiate(r),
+        };
         let users = r.extensions.get::<State<UsersKey>>().unwrap();
         let users = users.read().unwrap();
-        if let Some(q) = q {
+        let mut response = if let Some(q) = q {
             let (results, correction) = users.search(&q);
             let results = results.into_iter()
                 // Restrict search to 20 results, so the server isn't bogged
                 // down too much
                 .take(20)
                 .map(|(id, weight)| (users.get(&id).unwrap(), id, weight));
-            let body = views::search_results(r, &q, results, correction);
-            Ok(Response::with((status::Ok, body)))
+            if let Some(flavor) = flavor {
+                let body = text::search_results(
+                    &|id: &str| profile_url(r, id), flavor, &q, results, correction);
+                text_response(status::Ok, flavor, body)
+            } else {
+                let body = views::search_results(r, &users, &q, results, correction);
+                Response::with((status::Ok, body))
+            }
+        } else if let Some(flavor) = flavor {
+            // There's no search box in plain text, so say how to search
+            let path = format!("/{}", r.url.path().join("/"));
+            text_response(status::BadRequest, flavor, text::search_usage(flavor, &path))
         } else {
             let body = views::search(r);
-            Ok(Response::with((status::Ok, body)))
+            Response::with((status::Ok, body))
+        };
+        if extension.is_none() {
+            set_vary(&mut response);
+        }
+        Ok(response)
+    }
+
+    fn vcards(r: &mut Request) -> IronResult<Response> {
+        let q = query_param(r, "q");
+        let channel = query_param(r, "channel");
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let users = users.read().unwrap();
+        let ids: Vec<String> = if let Some(q) = q {
+            // Don't let one request dump the whole database
+            users.search(&q).0.into_iter().take(100).map(|(id, _)| id).collect()
+        } else if let Some(channel) = channel {
+            let channel = channel.trim_left_matches('#').to_lowercase();
+            users.channels().get(&channel).cloned().unwrap_or_else(Vec::new)
+        } else {
+            let body = views::not_found(r);
+            return Ok(Response::with((status::NotFound, body)));
+        };
+        let mut body = String::new();
+        for id in &ids {
+            if let Some(Ok(user)) = users.get(id) {
+                let profile_url = url_for!(r, "user", "id" => &id[..]).to_string();
+                body.push_str(&vcard::vcard(id, user, &profile_url));
+            }
         }
+        Ok(vcard_response(body))
     }
 
     fn random(r: &mut Request) -> IronResult<Response> {
@@ -146,6 +535,178 @@ fn main() {
         Ok(Response::with((status::Found, Redirect(url))))
     }
 
+    fn users_index(r: &mut Request) -> IronResult<Response> {
+        let order = match query_param(r, "sort") {
+            Some(name) => SortOrder::from_name(&name),
+            None => Some(SortOrder::Id),
+        };
+        // Pages are numbered from 1 in the URL
+        let page = match query_param(r, "page") {
+            Some(page) => page.parse::<usize>().ok().and_then(|page| page.checked_sub(1)),
+            None => Some(0),
+        };
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let users = users.read().unwrap();
+        // Only the first page can be empty
+        let exists = |page: usize| page == 0 || page.checked_mul(views::DIRECTORY_PAGE_SIZE)
+            .map_or(false, |start| start < users.len());
+        match (order, page) {
+            (Some(order), Some(page)) if exists(page) => {
+                let entries = users.sorted(order);
+                let body = views::directory(r, &entries, order, Some(page));
+                Ok(Response::with((status::Ok, body)))
+            },
+            _ => {
+                let body = views::not_found(r);
+                Ok(Response::with((status::NotFound, body)))
+            },
+        }
+    }
+
+    fn channels(r: &mut Request) -> IronResult<Response> {
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let users = users.read().unwrap();
+        let body = views::channels(r, users.channels(), users.channel_graph());
+        Ok(Response::with((status::Ok, body)))
+    }
+
+    fn channels_svg(r: &mut Request) -> IronResult<Response> {
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let users = users.read().unwrap();
+        let body = views::channel_graph(r, users.channel_graph(), &[]);
+        // A string, since maud would set the content type to HTML
+        let body = body.into_string();
+        let content_type: Mime = "image/svg+xml; charset=utf-8".parse().unwrap();
+        Ok(Response::with((status::Ok, content_type, body)))
+    }
+
+    fn channel(r: &mut Request) -> IronResult<Response> {
+        let route = r.extensions.get::<Router>().unwrap();
+        let name = route.find("name").unwrap().trim_left_matches('#').to_lowercase();
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let users = users.read().unwrap();
+        match users.channels().get(&name) {
+            Some(members) => {
+                let members: Vec<_> = members.iter()
+                    .filter_map(|id| users.get(id).and_then(Result::ok).map(|user| (&id[..], user)))
+                    .collect();
+                let overlapping = users.channel_graph().overlapping(&name);
+                let body = views::channel(r, &name, &members, &overlapping);
+                Ok(Response::with((status::Ok, body)))
+            },
+            None => {
+                let body = views::not_found(r);
+                Ok(Response::with((status::NotFound, body)))
+            },
+        }
+    }
+
+    fn feed(r: &mut Request) -> IronResult<Response> {
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let history = r.extensions.get::<State<HistoryKey>>().unwrap();
+        let body = feed::atom(r, &history.read().unwrap(), &users.read().unwrap());
+        let content_type: Mime = "application/atom+xml; charset=utf-8".parse().unwrap();
+        Ok(Response::with((status::Ok, content_type, body)))
+    }
+
+    fn api_changes(r: &mut Request) -> IronResult<Response> {
+        let since: Option<String> = r.get_ref::<UrlEncodedQuery>().ok()
+            .and_then(|query| query.get("since"))
+            .and_then(|since| since.first().cloned());
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let body = api::changes(&users.read().unwrap(), since.as_ref().map(|s| &s[..]));
+        let content_type: Mime = "application/json".parse().unwrap();
+        Ok(Response::with((status::Ok, content_type, body)))
+    }
+
+    fn exporter(format: Format) -> impl Fn(&mut Request) -> IronResult<Response> + Send + Sync {
+        move |r: &mut Request| {
+            let include_errors = r.get_ref::<UrlEncodedQuery>().ok()
+                .map_or(false, |query| query.contains_key("errors"));
+            let users = r.extensions.get::<State<UsersKey>>().unwrap();
+            let users = users.read().unwrap();
+            // The data only changes when it is reloaded, so the generation is
+            // enough to tell whether the client is up to date
+            let etag = EntityTag::strong(format!(
+                "{}-{}{}", users.generation(), format.name(),
+                if include_errors { "-errors" } else { "" }));
+            let not_modified = match r.headers.get::<IfNoneMatch>() {
+                Some(&IfNoneMatch::Any) => true,
+                Some(&IfNoneMatch::Items(ref tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+                None => false,
+            };
+            let mut response = if not_modified {
+                Response::with(status::NotModified)
+            } else {
+                let mut body = Vec::new();
+                itry!(export::export(&mut body, &users, format, include_errors));
+                let content_type: Mime = format.content_type().parse().unwrap();
+                Response::with((status::Ok, content_type, body))
+            };
+            response.headers.set(ETag(etag));
+            Ok(response)
+        }
+    }
+
+    fn graphql_handler() -> impl Handler {
+        GraphQLHandler::new(
+            |r: &mut Request| graphql::Context {
+                users: r.extensions.get::<State<UsersKey>>().unwrap().clone(),
+            },
+            graphql::Query,
+            juniper::EmptyMutation::new())
+    }
+
+    fn admin_status(r: &mut Request) -> IronResult<Response> {
+        if !is_admin(r) {
+            return Ok(Response::with((status::Forbidden, "Forbidden\n")));
+        }
+        let update_status = r.extensions.get::<Read<UpdaterKey>>().unwrap().status();
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let body = views::admin_status(r, &update_status, &users.read().unwrap());
+        Ok(Response::with((status::Ok, body)))
+    }
+
+    fn well_known_carddav(r: &mut Request) -> IronResult<Response> {
+        let url = url_for!(r, "carddav_root");
+        Ok(Response::with((status::MovedPermanently, Redirect(url))))
+    }
+
+    fn webfinger(r: &mut Request) -> IronResult<Response> {
+        let (resource, rels) = match r.get_ref::<UrlEncodedQuery>() {
+            Ok(query) => (
+                query.get("resource").and_then(|values| values.first().cloned()),
+                query.get("rel").cloned().unwrap_or_else(Vec::new),
+            ),
+            Err(_) => (None, Vec::new()),
+        };
+        let resource = match resource {
+            Some(resource) => resource,
+            None => return Ok(Response::with((status::BadRequest, "Missing resource\n"))),
+        };
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let users = users.read().unwrap();
+        let jrd = webfinger::lookup(&users, &resource, &rels, &|id: &str| profile_url(r, id));
+        let mut response = match jrd {
+            Some(jrd) => {
+                let content_type: Mime = "application/jrd+json".parse().unwrap();
+                Response::with((status::Ok, content_type, serde_json::to_string(&jrd).unwrap()))
+            },
+            None => Response::with((status::NotFound, "No such user\n")),
+        };
+        // Let tools in the browser look people up too
+        response.headers.set_raw("Access-Control-Allow-Origin", vec![b"*".to_vec()]);
+        Ok(response)
+    }
+
+    fn carddav(r: &mut Request) -> IronResult<Response> {
+        // Read the body first, so that a slow client doesn't hold the lock
+        let query = carddav::read_query(r)?;
+        let users = r.extensions.get::<State<UsersKey>>().unwrap();
+        let users = users.read().unwrap();
+        carddav::handle(r, &users, &query)
+    }
+
     fn not_found(r: &mut Request) -> IronResult<Response> {
         let body = views::not_found(r);
         Ok(Response::with((status::NotFound, body)))
@@ -155,17 +716,37 @@ fn main() {
 
     chain.link(Logger::new(None));
 
-    chain.link({
+    chain.link(Read::<UpdaterKey>::both(updater.clone()));
+    chain.link(Read::<AvatarsKey>::both(Avatars::from_env(&root_dir)));
+    let cards = Cards::from_env(&site_root.join("static"))
+        .map_err(|e| warn!("not drawing preview cards: {}", e))
+        .ok();
+    chain.link(Read::<CardsKey>::both(cards));
+
+    let history = Arc::new(RwLock::new(load_history(updater.repo_dir())));
+    chain.link(State::<HistoryKey>::both(history.clone()));
+
+    let users = {
         // Load user data
-        let data_dir = updater.data_dir().to_path_buf();
-        let users = Users::load(&data_dir).unwrap();
+        let layers = data_layers(updater.data_dir());
+        let mut users = Users::load(&layers).unwrap();
+        if let Ok(commit) = updater.head() {
+            users.set_commit(commit);
+        }
+        updater.accept();
         let arc = Arc::new(RwLock::new(users));
         // Reload data automatically when changed
         let arc_cloned = arc.clone();
+        let updater = updater.clone();
+        let gate = ReloadGate::from_env();
         thread::spawn(move || {
             let (tx, rx) = mpsc::channel();
             let mut watcher = notify::raw_watcher(tx).unwrap();
-            watcher.watch(&data_dir, RecursiveMode::NonRecursive).unwrap();
+            for layer in &layers {
+                if let Err(e) = watcher.watch(&layer.dir, RecursiveMode::NonRecursive) {
+                    warn!("not watching layer {} for changes: {}", layer.name, e);
+                }
+            }
             loop {
                 // Wait for a filesystem event
                 let event = rx.recv().unwrap();
@@ -181,14 +762,50 @@ fn main() {
                 }
                 // Reload the data
                 info!("reloading data!");
-                match Users::load(&data_dir) {
-                    Ok(users) => *arc_cloned.write().unwrap() = users,
-                    Err(e) => error!("error loading data: {}", e),
+                let mut users = match Users::load(&layers) {
+                    Ok(users) => users,
+                    Err(e) => {
+                        error!("error loading data: {}", e);
+                        continue;
+                    },
+                };
+                // Don't let a bad commit clobber perfectly good data
+                let check = gate.check(&arc_cloned.read().unwrap(), &users);
+                match check {
+                    Ok(()) => {
+                        users.inherit(&arc_cloned.read().unwrap());
+                        if let Ok(commit) = updater.head() {
+                            users.set_commit(commit);
+                        }
+                        *arc_cloned.write().unwrap() = users;
+                        updater.accept();
+                        *history.write().unwrap() = load_history(updater.repo_dir());
+                    },
+                    Err(rejection) => {
+                        error!("rejecting data update: {}", rejection);
+                        let errors = users.errors()
+                            .map(|(id, e)| (id.to_string(), e.to_string()))
+                            .collect();
+                        updater.reject(rejection.to_string(), errors);
+                    },
                 }
             }
         });
-        State::<UsersKey>::both(arc)
-    });
+        arc
+    };
+    chain.link(State::<UsersKey>::both(users.clone()));
+
+    if let Ok(addr) = env::var("KARKINOS_FINGER_ADDR") {
+        finger::start(&addr, users.clone(), public_profile_url).unwrap();
+    }
+
+    if let Ok(addr) = env::var("KARKINOS_GEMINI_ADDR") {
+        let identity = env::var_os("KARKINOS_GEMINI_IDENTITY")
+            .expect("KARKINOS_GEMINI_IDENTITY must be set to serve Gemini");
+        let password = env::var("KARKINOS_GEMINI_PASSWORD").unwrap_or_else(|_| String::new());
+        gemini::start(
+            &addr, Path::new(&identity), &password, users.clone(), public_profile_url).unwrap();
+    }
 
     if *IS_PRODUCTION {
         chain.link_before(|r: &mut Request| {
@@ -211,3 +828,138 @@ fn main() {
     info!("starting on {}", bind_addr);
     Iron::new(chain).http(bind_addr).unwrap();
 }
+
+/// Splits a file extension off a route parameter, so that `/user/bors.vcf`
+/// can be told apart from `/user/bors`.
+///
+/// GitHub usernames can't contain dots, so this is never ambiguous.
+fn split_extension(param: &str) -> (&str, Option<&str>) {
+    match param.rfind('.') {
+        Some(i) => (&param[..i], Some(&param[i + 1..])),
+        None => (param, None),
+    }
+}
+
+/// Checks that a request comes from someone allowed to see the admin pages:
+/// anyone with the `KARKINOS_ADMIN_TOKEN`, or if that isn't set, anyone on
+/// this machine. Behind the reverse proxy every request comes from this
+/// machine, so there the token is required.
+fn is_admin(r: &Request) -> bool {
+    match *ADMIN_TOKEN {
+        Some(ref token) => {
+            let expected = format!("Bearer {}", token);
+            r.headers.get_raw("Authorization")
+                .and_then(|values| values.first())
+                .map_or(false, |value| same_bytes(value, expected.as_bytes()))
+        },
+        None => !*IS_PRODUCTION && r.remote_addr.ip().is_loopback(),
+    }
+}
+
+/// Compares two strings in time that only depends on their lengths, so that
+/// a token can't be guessed a byte at a time.
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn query_param(r: &mut Request, name: &str) -> Option<String> {
+    r.get_ref::<UrlEncodedQuery>().ok()
+        .and_then(|query| query.get(name))
+        .and_then(|values| values.first().cloned())
+}
+
+fn profile_url(r: &Request, id: &str) -> String {
+    url_for!(r, "user", "id" => id).to_string()
+}
+
+/// Like `profile_url`, but for when there's no request to go on.
+fn public_profile_url(id: &str) -> String {
+    format!("{}/user/{}", *BASE_URL, id)
+}
+
+fn text_response(status: status::Status, flavor: Flavor, body: String) -> Response {
+    let content_type: Mime = flavor.content_type().parse().unwrap();
+    // These usually end up on a terminal
+    Response::with((status, content_type, text::printable(&body)))
+}
+
+/// Tells caches that the response depends on the headers that
+/// `Flavor::negotiate` looks at.
+fn set_vary(response: &mut Response) {
+    response.headers.set_raw("Vary", vec![b"Accept, User-Agent".to_vec()]);
+}
+
+fn vcard_response(body: String) -> Response {
+    let content_type: Mime = "text/vcard; charset=utf-8".parse().unwrap();
+    Response::with((status::Ok, content_type, body))
+}
+
+/// Reads the history of the data repository, or starts without one if it
+/// can't be read.
+fn load_history(repo_dir: &Path) -> History {
+    History::load(repo_dir).unwrap_or_else(|e| {
+        error!("error loading history: {}", e);
+        History::default()
+    })
+}
+
+/// Returns the data directories to load, from bottom to top.
+fn data_layers(data_dir: &Path) -> Vec<Layer> {
+    let mut layers = vec![Layer::new("rustaceans.org", data_dir)];
+    layers.extend(Layer::from_env());
+    layers
+}
+
+/// Loads the data that the server last fetched, for the subcommands, which
+/// don't fetch anything themselves.
+fn load_data(root_dir: &Path) -> Result<Users, String> {
+    let repo_dir = update::repo_dir(root_dir);
+    if !repo_dir.is_dir() {
+        return Err(format!("no data in {}; run the server once to fetch it",
+                           repo_dir.display()));
+    }
+    Users::load(&data_layers(&update::data_dir(&repo_dir))).map_err(|e| e.to_string())
+}
+
+/// Dumps every entry to standard output.
+///
+/// Usage: `karkinos export [--format json|jsonl|csv] [--include-errors]`
+fn export_command(root_dir: &Path, args: &[String]) -> Result<(), String> {
+    let mut format = Format::Json;
+    let mut include_errors = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--format" => {
+                let name = args.next().ok_or("--format needs a value")?;
+                format = Format::from_name(name)
+                    .ok_or_else(|| format!("unknown format: {}", name))?;
+            },
+            "--include-errors" => include_errors = true,
+            arg => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
+    let users = load_data(root_dir)?;
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    export::export(&mut out, &users, format, include_errors).map_err(|e| e.to_string())
+}
+
+/// Renders the whole site to static files.
+///
+/// Usage: `karkinos build --out <dir>`
+fn build_command(root_dir: &Path, site_root: &Path, args: &[String]) -> Result<(), String> {
+    let mut out_dir = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--out" => out_dir = Some(args.next().ok_or("--out needs a value")?),
+            arg => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
+    let out_dir = out_dir.ok_or("--out is required")?;
+    let users = load_data(root_dir)?;
+    let history = load_history(&update::repo_dir(root_dir));
+    site::build(Path::new(out_dir), &site_root.join("static"), &users, &history, &BASE_URL)
+        .map_err(|e| e.to_string())
+}
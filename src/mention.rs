@@ -0,0 +1,161 @@
+//! Finds people mentioning each other in their notes, like "co-maintainer
+//! with @someone".
+
+use pulldown_cmark::{self, Event, Parser, Tag};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use models::User;
+
+/// Parses someone's notes, pairing each event with whether it's text that
+/// can mention people. Code, and the text of links and images, can't.
+pub fn parse<'a>(notes: &'a str) -> impl Iterator<Item = (Event<'a>, bool)> {
+    let mut nested = 0;
+    Parser::new_ext(notes, pulldown_cmark::OPTION_ENABLE_TABLES).map(move |event| {
+        match event {
+            Event::Start(Tag::Link(..)) | Event::Start(Tag::Image(..)) |
+            Event::Start(Tag::Code) | Event::Start(Tag::CodeBlock(..)) => nested += 1,
+            Event::End(Tag::Link(..)) | Event::End(Tag::Image(..)) |
+            Event::End(Tag::Code) | Event::End(Tag::CodeBlock(..)) => nested -= 1,
+            _ => {},
+        }
+        let linkable = match event {
+            Event::Text(_) => nested == 0,
+            _ => false,
+        };
+        (event, linkable)
+    })
+}
+
+/// Finds the people mentioned in some text, either as `@id` or as an id
+/// standing on its own.
+///
+/// Most ids are ordinary words too, so an id on its own only counts if it
+/// has a hyphen or a digit in it, like `rust-lang` or `ferris42`.
+///
+/// `lookup` is given each word that might be a mention, and whether it was
+/// written with an `@`. It returns the id that the word refers to, if any.
+/// Each result covers the whole mention, including the `@`.
+pub fn find<'a, F>(text: &str, mut lookup: F) -> Vec<(Range<usize>, &'a str)> where
+    F: FnMut(&str, bool) -> Option<&'a str>
+{
+    let mut found = Vec::new();
+    let mut rest = 0;
+    while let Some(offset) = text[rest..].find(is_id_char) {
+        let start = rest + offset;
+        let end = text[start..].find(|c| !is_id_char(c)).map_or(text.len(), |len| start + len);
+        rest = end;
+        // GitHub ids can have hyphens in them, but not at either end
+        let word = text[start..end].trim_matches('-');
+        let start = start + text[start..end].find(word).unwrap_or(0);
+        let end = start + word.len();
+        if word.is_empty() {
+            continue;
+        }
+        let before = text[..start].chars().next_back();
+        let after = text[end..].chars().next();
+        // Skip email addresses, domain names and paths
+        let inside_address = after == Some('@') || after == Some('/') ||
+            (after == Some('.') && text[end + 1..].starts_with(is_id_char));
+        if before == Some('@') {
+            let at = start - 1;
+            let prefix = text[..at].chars().next_back();
+            if prefix.map_or(true, |c| !is_id_char(c) && c != '.') && !inside_address {
+                if let Some(id) = lookup(word, true) {
+                    found.push((at..end, id));
+                }
+            }
+        } else if before.map_or(true, |c| !"/.:#_&=".contains(c)) && !inside_address &&
+            word.contains(|c: char| c == '-' || c.is_ascii_digit())
+        {
+            if let Some(id) = lookup(word, false) {
+                found.push((start..end, id));
+            }
+        }
+    }
+    found
+}
+
+fn is_id_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-'
+}
+
+/// Works out who each person mentions in their notes, in the places that
+/// `views` will link them.
+///
+/// Mentions with an `@` ignore case, like they do on GitHub. Bare ids have
+/// to match exactly. Nobody is counted as mentioning themselves.
+pub fn index(data: &BTreeMap<String, Result<User, String>>) -> BTreeMap<String, Vec<String>> {
+    let known: BTreeMap<String, &str> = data.keys()
+        .map(|id| (id.to_lowercase(), &id[..]))
+        .collect();
+    let mut mentions = BTreeMap::new();
+    for (id, user) in data {
+        let notes = match *user {
+            Ok(User { notes: Some(ref notes), .. }) => notes,
+            _ => continue,
+        };
+        let mut mentioned = Vec::new();
+        for (event, linkable) in parse(notes) {
+            let text = match event {
+                Event::Text(ref text) if linkable => text,
+                _ => continue,
+            };
+            let found = find(text, |word, at| {
+                known.get(&word.to_lowercase()).cloned().and_then(|other| {
+                    if at || other == word { Some(other) } else { None }
+                })
+            });
+            mentioned.extend(found.into_iter().map(|(_, other)| other.to_string()));
+        }
+        let mut mentioned: Vec<String> = mentioned.into_iter()
+            .filter(|other| other != id)
+            .collect();
+        mentioned.sort();
+        mentioned.dedup();
+        if !mentioned.is_empty() {
+            mentions.insert(id.clone(), mentioned);
+        }
+    }
+    mentions
+}
+
+#[test]
+fn mentions() {
+    let known = ["someone", "Dawn", "rust-lang", "crab42"];
+    let lookup = |word: &str, at: bool| {
+        known.iter().cloned().find(|id| {
+            if at { id.eq_ignore_ascii_case(word) } else { *id == word }
+        })
+    };
+    let text = "Co-maintainer with @Someone, crab42 and Dawn (dawn@example.com). \
+                See github.com/rust-lang and @nobody; someone-else isn't here.";
+    let found: Vec<(&str, &str)> = find(text, lookup).into_iter()
+        .map(|(range, id)| (&text[range], id))
+        .collect();
+    assert_eq!(found, vec![("@Someone", "someone"), ("crab42", "crab42")]);
+}
+
+#[test]
+fn index_notes() {
+    use serde_json;
+
+    let mut data: BTreeMap<String, Result<User, String>> = BTreeMap::new();
+    {
+        let mut add = |id: &str, notes: &str| {
+            let mut user = User::from_value(serde_json::from_str("{}").unwrap()).unwrap();
+            user.notes = Some(notes.to_string());
+            data.insert(id.to_string(), Ok(user));
+        };
+        add("ferris", "Works with @CORRO and crab-42, but not Crab-42. I'm @ferris.");
+        add("corro", "See `@ferris` and [@ferris's blog](http://example.com/).");
+        add("crab-42", "Ask ferris, or\n\n    @corro\n");
+    }
+    data.insert("broken".to_string(), Err("@ferris".to_string()));
+
+    let mentions = index(&data);
+    assert_eq!(mentions["ferris"], vec!["corro".to_string(), "crab-42".to_string()]);
+    assert!(!mentions.contains_key("corro"));
+    assert!(!mentions.contains_key("crab-42"));
+    assert!(!mentions.contains_key("broken"));
+}
@@ -1,15 +1,21 @@
 use rand::{self, Rng};
-use serde_json;
+use serde_json::{self, Map, Value};
 use std::collections::BTreeMap;
+use std::env;
 use std::ffi::OsStr;
 use std::fmt::{self, Display, Formatter};
 use std::fs::{self, File};
 use std::io::{self, BufReader};
-use std::path::Path;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use graph::ChannelGraph;
+use mention;
 use search::SearchIndex;
+use similar;
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct User {
     // NOTE: when changing these fields, be sure to update
     // `.remove_empty_strings()` and `.with_str_fields()` below
@@ -26,12 +32,19 @@ pub struct User {
     pub blog: Option<String>,
     pub website: Option<String>,
     pub notes: Option<String>,
+    /// Any fields we don't know about, such as those added by a private data
+    /// layer.
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
 }
 
 impl User {
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<User, LoadUserError> {
-        let reader = BufReader::new(File::open(path)?);
-        let mut user: User = serde_json::from_reader(reader)?;
+        User::from_value(read_json(path)?)
+    }
+
+    pub fn from_value(value: Value) -> Result<User, LoadUserError> {
+        let mut user: User = serde_json::from_value(value)?;
         user.remove_empty_strings();
         for channel in &mut user.irc_channels {
             if channel.starts_with('#') {
@@ -61,6 +74,14 @@ impl User {
             }
         }
         fixup!(name irc email discourse reddit twitter blog website notes);
+        let extra = mem::replace(&mut self.extra, BTreeMap::new());
+        self.extra = extra.into_iter()
+            .filter(|&(_, ref value)| match *value {
+                Value::Null => false,
+                Value::String(ref s) => !is_whitespace(s),
+                _ => true,
+            })
+            .collect();
     }
 
     /// Applies the given callback to every searchable field in this entry.
@@ -85,6 +106,32 @@ impl User {
         for channel in &self.irc_channels {
             callback(channel, 1);
         }
+        for value in self.extra.values() {
+            if let Value::String(ref s) = *value {
+                callback(s, 1);
+            }
+        }
+    }
+}
+
+/// The sites that entries name their accounts on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Service {
+    GitHub,
+    Discourse,
+    Reddit,
+    Twitter,
+}
+
+impl Service {
+    /// Returns the address of the named account.
+    pub fn url(self, name: &str) -> String {
+        match self {
+            Service::GitHub => format!("https://github.com/{}", name),
+            Service::Discourse => format!("https://users.rust-lang.org/users/{}", name),
+            Service::Reddit => format!("https://reddit.com/user/{}", name),
+            Service::Twitter => format!("https://twitter.com/{}", name),
+        }
     }
 }
 
@@ -92,28 +139,190 @@ fn is_whitespace(s: &str) -> bool {
     s.chars().all(char::is_whitespace)
 }
 
+fn read_json<P: AsRef<Path>>(path: P) -> Result<Value, LoadUserError> {
+    let reader = BufReader::new(File::open(path)?);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// Applies a JSON merge patch (RFC 7386) to `target`.
+///
+/// Fields in `patch` replace those in `target`, except that `null` removes the
+/// field instead.
+fn merge_patch(target: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            let target = target.as_object_mut().unwrap();
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(&key);
+                } else {
+                    if !target.contains_key(&key) {
+                        target.insert(key.clone(), Value::Null);
+                    }
+                    merge_patch(target.get_mut(&key).unwrap(), value);
+                }
+            }
+        },
+        patch => *target = patch,
+    }
+}
+
+/// A directory of entries, which can be stacked on top of other directories.
+///
+/// Each entry in a layer is a JSON merge patch over the same entry in the
+/// layers below it. So a layer can add new entries, override or add fields in
+/// existing ones, or hide an entry completely by setting it to `null`.
+#[derive(Clone, Debug)]
+pub struct Layer {
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+impl Layer {
+    pub fn new<S: Into<String>, P: Into<PathBuf>>(name: S, dir: P) -> Layer {
+        Layer { name: name.into(), dir: dir.into() }
+    }
+
+    /// Reads extra layers from the `KARKINOS_DATA_LAYERS` environment
+    /// variable, which lists directories in the same way as `PATH`.
+    ///
+    /// Each layer is named after its directory.
+    pub fn from_env() -> Vec<Layer> {
+        let dirs = match env::var_os("KARKINOS_DATA_LAYERS") {
+            Some(dirs) => dirs,
+            None => return Vec::new(),
+        };
+        env::split_paths(&dirs)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(|dir| {
+                let name = dir.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| dir.to_string_lossy().into_owned());
+                Layer::new(name, dir)
+            })
+            .collect()
+    }
+}
+
+/// The number of changes to remember across reloads.
+const MAX_CHANGES: usize = 10_000;
+
+/// The number of commits to remember across reloads.
+const MAX_COMMITS: usize = 1_000;
+
+/// Something that happened to an entry when the data was reloaded.
+#[derive(Clone, Debug)]
+pub struct Change {
+    /// The generation that the change first appeared in.
+    pub generation: u64,
+    pub id: String,
+    pub kind: ChangeKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
 #[derive(Debug)]
 pub struct Users {
     data: BTreeMap<String, Result<User, String>>,
+    /// The names of the layers that contributed to each entry.
+    origins: BTreeMap<String, Vec<String>>,
     index: SearchIndex<String>,
+    /// The people most like each person, best first.
+    similar: BTreeMap<String, Vec<String>>,
+    /// The people each person mentions in their notes.
+    mentions: BTreeMap<String, Vec<String>>,
+    /// The people who mention each person in their notes.
+    mentioned_by: BTreeMap<String, Vec<String>>,
+    /// The members of each IRC channel.
+    channels: BTreeMap<String, Vec<String>>,
+    /// How the IRC channels overlap.
+    channel_graph: ChannelGraph,
+    /// Goes up by one on every reload.
+    ///
+    /// This starts from the time the server was started, so that it keeps
+    /// going up across restarts.
+    generation: u64,
+    /// The oldest generation that `changes` can bring up to date.
+    log_start: u64,
+    /// Every change since `log_start`, oldest first.
+    changes: Vec<Change>,
+    /// The commits that each generation was loaded from, oldest first.
+    commits: Vec<(u64, String)>,
 }
 
 impl Users {
-    pub fn load<P: AsRef<Path>>(data_dir: P) -> Result<Users, LoadUserError> {
+    /// Loads every entry from the given layers, with later layers taking
+    /// precedence over earlier ones.
+    pub fn load(layers: &[Layer]) -> Result<Users, LoadUserError> {
+        let mut values = BTreeMap::new();
+        let mut origins = BTreeMap::new();
+        for layer in layers {
+            if !layer.dir.is_dir() {
+                warn!("skipping layer {}: {} is not a directory", layer.name, layer.dir.display());
+                continue;
+            }
+            for entry in fs::read_dir(&layer.dir)? {
+                let path = entry?.path();
+                if path.extension() == Some(OsStr::new("json")) {
+                    let id = path.file_stem().unwrap().to_string_lossy().into_owned();
+                    let patch = read_json(&path).map_err(|e| {
+                        warn!("could not parse entry for {} in {}: {}", id, layer.name, e);
+                        e.to_string()
+                    });
+                    let value = match (values.remove(&id), patch) {
+                        (_, Err(e)) => Err(e),
+                        (Some(Ok(mut value)), Ok(patch)) => {
+                            merge_patch(&mut value, patch);
+                            Ok(value)
+                        },
+                        // A broken entry can only be replaced wholesale
+                        (Some(Err(e)), Ok(patch)) =>
+                            if patch.is_object() { Err(e) } else { Ok(patch) },
+                        (None, Ok(patch)) => {
+                            let mut value = Value::Null;
+                            merge_patch(&mut value, patch);
+                            Ok(value)
+                        },
+                    };
+                    values.insert(id.clone(), value);
+                    origins.entry(id).or_insert_with(Vec::new).push(layer.name.clone());
+                }
+            }
+        }
         let mut data = BTreeMap::new();
-        for entry in fs::read_dir(data_dir.as_ref())? {
-            let path = entry?.path();
-            if path.extension() == Some(OsStr::new("json")) {
-                let id = path.file_stem().unwrap().to_string_lossy().into_owned();
-                // Some users' entries actually fail to parse!
-                // Instead of bailing on these, just record the error and move on.
-                let user = User::from_path(&path).map_err(|e| {
+        for (id, value) in values {
+            // Some users' entries actually fail to parse!
+            // Instead of bailing on these, just record the error and move on.
+            let user = match value {
+                Ok(Value::Null) => {
+                    // Hidden by a later layer
+                    origins.remove(&id);
+                    continue;
+                },
+                Ok(value) => User::from_value(value).map_err(|e| {
                     warn!("could not parse entry for {}: {}", id, e);
                     e.to_string()
-                });
-                data.insert(id, user);
-            }
+                }),
+                Err(e) => Err(e),
+            };
+            data.insert(id, user);
         }
+        Ok(Users::new(data, origins))
+    }
+
+    /// Builds the indexes over the given entries.
+    fn new(
+        data: BTreeMap<String, Result<User, String>>, origins: BTreeMap<String, Vec<String>>)
+        -> Users
+    {
         let mut index = SearchIndex::new();
         for (id, user) in &data {
             index.add(id.clone(), id, 10);
@@ -121,8 +330,125 @@ impl Users {
                 user.with_str_fields(|s, w| index.add(id.clone(), s, w));
             }
         }
+        let similar = similar::similar(&data, &index);
+        let mentions = mention::index(&data);
+        let mut mentioned_by = BTreeMap::new();
+        for (id, mentioned) in &mentions {
+            for other in mentioned {
+                mentioned_by.entry(other.clone()).or_insert_with(Vec::new).push(id.clone());
+            }
+        }
+        let channels = group_channels(&data);
+        let channel_graph = ChannelGraph::new(&channels);
         info!("loaded {} rustaceans", data.len());
-        Ok(Users { data: data, index: index })
+        let generation = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Users {
+            data: data,
+            origins: origins,
+            index: index,
+            similar: similar,
+            mentions: mentions,
+            mentioned_by: mentioned_by,
+            channels: channels,
+            channel_graph: channel_graph,
+            generation: generation,
+            log_start: generation,
+            changes: Vec::new(),
+            commits: Vec::new(),
+        }
+    }
+
+    /// Takes over the change log from the data that this replaces, and adds
+    /// whatever changed since then.
+    pub fn inherit(&mut self, previous: &Users) {
+        self.generation = previous.generation + 1;
+        self.log_start = previous.log_start;
+        self.changes = previous.changes.clone();
+        self.commits = previous.commits.clone();
+        for (id, user) in &self.data {
+            let kind = match previous.data.get(id) {
+                None => ChangeKind::Added,
+                Some(old) if old != user => ChangeKind::Modified,
+                Some(_) => continue,
+            };
+            self.changes.push(Change { generation: self.generation, id: id.clone(), kind: kind });
+        }
+        for id in previous.data.keys() {
+            if !self.data.contains_key(id) {
+                self.changes.push(Change {
+                    generation: self.generation,
+                    id: id.clone(),
+                    kind: ChangeKind::Deleted,
+                });
+            }
+        }
+        if self.changes.len() > MAX_CHANGES {
+            let excess = self.changes.len() - MAX_CHANGES;
+            // Anyone who hasn't seen the last generation we dropped will have
+            // to start over
+            self.log_start = self.changes[excess - 1].generation;
+            self.changes.drain(..excess);
+        }
+    }
+
+    /// Records the commit that this data was loaded from.
+    pub fn set_commit(&mut self, commit: String) {
+        self.commits.push((self.generation, commit));
+        if self.commits.len() > MAX_COMMITS {
+            let excess = self.commits.len() - MAX_COMMITS;
+            self.commits.drain(..excess);
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Turns a cursor into a generation. The cursor can be either a generation
+    /// number, or (a prefix of) a commit that was loaded before.
+    pub fn resolve_cursor(&self, cursor: &str) -> Option<u64> {
+        if cursor.len() >= 7 {
+            let found = self.commits.iter().rev()
+                .find(|&&(_, ref commit)| commit.starts_with(cursor))
+                .map(|&(generation, _)| generation);
+            if found.is_some() {
+                return found;
+            }
+        }
+        cursor.parse().ok()
+    }
+
+    /// Returns every entry that changed after the given generation, with the
+    /// changes for each entry squashed together.
+    ///
+    /// Returns `None` if the change log doesn't go back that far.
+    pub fn changes_since(&self, since: u64) -> Option<Vec<(&str, ChangeKind)>> {
+        if since < self.log_start || since > self.generation {
+            return None;
+        }
+        let mut first_changes = BTreeMap::new();
+        for change in &self.changes {
+            if change.generation > since {
+                first_changes.entry(&change.id[..]).or_insert(change.kind);
+            }
+        }
+        Some(first_changes.into_iter().filter_map(|(id, first)| {
+            let exists = self.data.contains_key(id);
+            match (first, exists) {
+                (ChangeKind::Added, true) => Some((id, ChangeKind::Added)),
+                // Added and then deleted again, so they never knew about it
+                (ChangeKind::Added, false) => None,
+                (_, true) => Some((id, ChangeKind::Modified)),
+                (_, false) => Some((id, ChangeKind::Deleted)),
+            }
+        }).collect())
+    }
+
+    /// Iterates over every entry, in order of id.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item=(&'a str, Result<&'a User, &'a str>)> + 'a {
+        self.data.iter().map(|(id, user)| (&id[..], user.as_ref().map_err(|e| &e[..])))
     }
 
     pub fn random_id(&self) -> Option<&str> {
@@ -134,9 +460,135 @@ impl Users {
         self.data.get(id).map(|r| r.as_ref().map_err(|e| &e[..]))
     }
 
+    /// Returns the names of the layers that contributed to the given entry,
+    /// from bottom to top.
+    pub fn origins(&self, id: &str) -> &[String] {
+        self.origins.get(id).map(|origins| &origins[..]).unwrap_or(&[])
+    }
+
+    /// Returns the number of entries, including those that failed to parse.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns the entries that failed to parse, along with their errors.
+    pub fn errors<'a>(&'a self) -> impl Iterator<Item=(&'a str, &'a str)> + 'a {
+        self.data.iter().filter_map(|(id, user)| match *user {
+            Ok(_) => None,
+            Err(ref e) => Some((&id[..], &e[..])),
+        })
+    }
+
     pub fn search(&self, query: &str) -> (Vec<(String, u64)>, Option<String>) {
         self.index.query(query)
     }
+
+    /// Lists every entry in the given order, ignoring case.
+    pub fn sorted(&self, order: SortOrder) -> Vec<(&str, Result<&User, &str>)> {
+        let mut entries: Vec<_> = self.iter().collect();
+        entries.sort_by_key(|&(id, user)| (order.key(id, user), id));
+        entries
+    }
+
+    /// Returns the people who have the most in common with the given one,
+    /// as worked out when the data was loaded.
+    pub fn similar(&self, id: &str) -> Vec<(&str, &User)> {
+        self.similar.get(id).map_or(vec![], |ids| self.lookup_all(ids))
+    }
+
+    /// Returns the ids of the people that the given one mentions in their
+    /// notes.
+    pub fn mentions(&self, id: &str) -> &[String] {
+        self.mentions.get(id).map_or(&[][..], |ids| &ids[..])
+    }
+
+    /// Returns the people who mention the given one in their notes.
+    pub fn mentioned_by(&self, id: &str) -> Vec<(&str, &User)> {
+        self.mentioned_by.get(id).map_or(vec![], |ids| self.lookup_all(ids))
+    }
+
+    /// Looks up several people at once, skipping any whose entries are
+    /// broken.
+    fn lookup_all<'a>(&'a self, ids: &'a [String]) -> Vec<(&'a str, &'a User)> {
+        ids.iter()
+            .filter_map(|id| match self.data.get(id) {
+                Some(&Ok(ref user)) => Some((&id[..], user)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn search_index(&self) -> &SearchIndex<String> {
+        &self.index
+    }
+
+    /// Returns the entries grouped by IRC channel, ignoring case. The members
+    /// of each channel are listed in order of id.
+    pub fn channels(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.channels
+    }
+
+    /// Returns how the IRC channels overlap.
+    pub fn channel_graph(&self) -> &ChannelGraph {
+        &self.channel_graph
+    }
+}
+
+fn group_channels(data: &BTreeMap<String, Result<User, String>>) -> BTreeMap<String, Vec<String>> {
+    let mut channels = BTreeMap::new();
+    for (id, user) in data {
+        if let Ok(ref user) = *user {
+            for channel in &user.irc_channels {
+                let members = channels.entry(channel.to_lowercase()).or_insert_with(Vec::new);
+                // Some people list the same channel twice
+                if members.last() != Some(id) {
+                    members.push(id.clone());
+                }
+            }
+        }
+    }
+    channels
+}
+
+/// How to order entries in a listing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortOrder {
+    Id,
+    /// By display name, falling back to the id for those who haven't given
+    /// one.
+    Name,
+}
+
+impl SortOrder {
+    pub fn from_name(name: &str) -> Option<SortOrder> {
+        match name {
+            "id" => Some(SortOrder::Id),
+            "name" => Some(SortOrder::Name),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SortOrder::Id => "id",
+            SortOrder::Name => "name",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SortOrder::Id => "GitHub id",
+            SortOrder::Name => "name",
+        }
+    }
+
+    /// Returns the text that an entry is sorted by.
+    pub fn key(self, id: &str, user: Result<&User, &str>) -> String {
+        match (self, user) {
+            (SortOrder::Name, Ok(&User { name: Some(ref name), .. })) => name.to_lowercase(),
+            _ => id.to_lowercase(),
+        }
+    }
 }
 
 /// An error encountered while loading the set of users.
@@ -180,3 +632,86 @@ fn smoke() {
     "#;
     let _: User = serde_json::from_str(DATA).unwrap();
 }
+
+#[test]
+fn layers() {
+    let mut value: Value = serde_json::from_str(r#"
+        {
+            "name": "Bors",
+            "irc": "bors",
+            "irc_channels": ["rust"]
+        }
+    "#).unwrap();
+    let patch: Value = serde_json::from_str(r#"
+        {
+            "irc": null,
+            "irc_channels": ["rust-internals"],
+            "desk": "Level 3"
+        }
+    "#).unwrap();
+    merge_patch(&mut value, patch);
+    let user = User::from_value(value).unwrap();
+    assert_eq!(user.name, Some("Bors".to_string()));
+    assert_eq!(user.irc, None);
+    assert_eq!(user.irc_channels, vec!["rust-internals".to_string()]);
+    assert_eq!(user.extra.get("desk").and_then(|v| v.as_str()), Some("Level 3"));
+}
+
+/// Makes an empty directory for a test to write to.
+#[cfg(test)]
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("karkinos-{}-{}", name, ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+impl Users {
+    /// Builds the directory from entries given as JSON, as if they had been
+    /// loaded from a single layer.
+    pub fn from_json(entries: &[(&str, &str)]) -> Users {
+        let mut data = BTreeMap::new();
+        let mut origins = BTreeMap::new();
+        for &(id, json) in entries {
+            let user = serde_json::from_str(json)
+                .map_err(LoadUserError::from)
+                .and_then(User::from_value)
+                .map_err(|e| e.to_string());
+            data.insert(id.to_string(), user);
+            origins.insert(id.to_string(), vec!["test".to_string()]);
+        }
+        Users::new(data, origins)
+    }
+}
+
+#[test]
+fn load_layers() {
+    use std::io::Write;
+
+    let write = |dir: &Path, id: &str, json: &str| {
+        File::create(dir.join(format!("{}.json", id))).unwrap()
+            .write_all(json.as_bytes()).unwrap();
+    };
+    let bottom = temp_dir("layers-bottom");
+    write(&bottom, "ferris", r#"{ "name": "Ferris", "irc": "ferris" }"#);
+    write(&bottom, "corro", r#"{ "name": "Corro" }"#);
+    let top = temp_dir("layers-top");
+    write(&top, "ferris", r#"{ "irc": null, "desk": "Level 3" }"#);
+    write(&top, "corro", "null");
+    write(&top, "crab", r#"{ "name": "Crab" }"#);
+    let layers = [
+        Layer::new("bottom", bottom),
+        Layer::new("missing", temp_dir("layers-missing").join("missing")),
+        Layer::new("top", top),
+    ];
+
+    let users = Users::load(&layers).unwrap();
+    let ferris = users.get("ferris").unwrap().unwrap();
+    assert_eq!(ferris.name, Some("Ferris".to_string()));
+    assert_eq!(ferris.irc, None);
+    assert_eq!(ferris.extra.get("desk").and_then(|v| v.as_str()), Some("Level 3"));
+    assert!(users.get("corro").is_none());
+    assert_eq!(users.get("crab").unwrap().unwrap().name, Some("Crab".to_string()));
+    assert_eq!(users.origins("ferris"), &["bottom".to_string(), "top".to_string()][..]);
+}
@@ -0,0 +1,140 @@
+//! oEmbed, so that links to profiles can be turned into cards by wikis and
+//! blogs that support it.
+
+use maud::html;
+
+use models::{Service, Users};
+use views::{self, Urls};
+
+/// How big the embedded card is, unless the consumer asks for smaller.
+const WIDTH: u32 = 400;
+const HEIGHT: u32 = 240;
+
+#[derive(Debug, Serialize)]
+pub struct OEmbed {
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    author_name: String,
+    author_url: String,
+    provider_name: &'static str,
+    provider_url: String,
+    html: String,
+    width: u32,
+    height: u32,
+    #[serde(flatten)]
+    thumbnail: Option<Thumbnail>,
+}
+
+#[derive(Debug, Serialize)]
+struct Thumbnail {
+    thumbnail_url: String,
+    thumbnail_width: u32,
+    thumbnail_height: u32,
+}
+
+/// Describes how to embed the profile at the given address, fitting within
+/// the maximum size if one is given.
+///
+/// `card` is whether there's a preview card to offer as a thumbnail.
+pub fn lookup(
+    r: &Urls, users: &Users, url: &str, max_width: Option<u32>, max_height: Option<u32>,
+    card: bool)
+    -> Option<OEmbed>
+{
+    let id = url.trim_right_matches('/').rsplit('/').next()?;
+    // Whether it was linked over HTTP or HTTPS doesn't matter
+    if without_scheme(url) != without_scheme(&r.route("user", &[("id", id)])) {
+        return None;
+    }
+    let user = users.get(id)?.ok()?;
+    let width = max_width.map_or(WIDTH, |max| max.min(WIDTH));
+    let height = max_height.map_or(HEIGHT, |max| max.min(HEIGHT));
+    let html = html! {
+        iframe src=(r.route("embed_user", &[("id", id)])) width=(width) height=(height)
+            frameborder="0" title=(views::user_title(id, Some(user))) {}
+    };
+    Some(OEmbed {
+        version: "1.0",
+        kind: "rich",
+        title: views::user_title(id, Some(user)),
+        author_name: id.to_string(),
+        author_url: Service::GitHub.url(id),
+        provider_name: "Karkinos",
+        provider_url: r.route("home", &[]),
+        html: html.into_string(),
+        width: width,
+        height: height,
+        thumbnail: if card {
+            Some(Thumbnail {
+                thumbnail_url: r.route("user_card", &[("id", id)]),
+                thumbnail_width: 1200,
+                thumbnail_height: 630,
+            })
+        } else {
+            None
+        },
+    })
+}
+
+fn without_scheme(url: &str) -> &str {
+    let url = url.trim_right_matches('/');
+    url.find("://").map_or(url, |i| &url[i + 3..])
+}
+
+#[cfg(test)]
+fn test_users() -> Users {
+    Users::from_json(&[("ferris", r#"{ "name": "Ferris" }"#)])
+}
+
+#[test]
+fn json() {
+    use serde_json::{self, Value};
+    use views::TestUrls;
+
+    let users = test_users();
+    let oembed = lookup(&TestUrls, &users, "https://karkinos.example/user/ferris",
+                        None, None, true).unwrap();
+    let json: Value = serde_json::to_value(&oembed).unwrap();
+    assert_eq!(json["version"], "1.0");
+    assert_eq!(json["type"], "rich");
+    assert_eq!(json["author_name"], "ferris");
+    assert_eq!(json["provider_url"], "https://karkinos.example/");
+    assert_eq!(json["width"], 400);
+    assert_eq!(json["height"], 240);
+    assert_eq!(json["thumbnail_url"], "https://karkinos.example/user/ferris/card.png");
+    assert!(json["html"].as_str().unwrap()
+        .starts_with("<iframe src=\"https://karkinos.example/embed/user/ferris\""));
+
+    let oembed = lookup(&TestUrls, &users, "https://karkinos.example/user/ferris",
+                        None, None, false).unwrap();
+    let json: Value = serde_json::to_value(&oembed).unwrap();
+    assert!(json.get("thumbnail_url").is_none());
+}
+
+#[test]
+fn sizes() {
+    use views::TestUrls;
+
+    let users = test_users();
+    let url = "https://karkinos.example/user/ferris";
+    let oembed = lookup(&TestUrls, &users, url, Some(300), Some(1000), true).unwrap();
+    assert_eq!((oembed.width, oembed.height), (300, 240));
+    let oembed = lookup(&TestUrls, &users, url, None, Some(100), true).unwrap();
+    assert_eq!((oembed.width, oembed.height), (400, 100));
+}
+
+#[test]
+fn urls() {
+    use views::TestUrls;
+
+    let users = test_users();
+    let found = |url: &str| lookup(&TestUrls, &users, url, None, None, true).is_some();
+    assert!(found("https://karkinos.example/user/ferris"));
+    assert!(found("http://karkinos.example/user/ferris/"));
+    assert!(!found("https://elsewhere.example/user/ferris"));
+    assert!(!found("https://karkinos.example/channel/ferris"));
+    assert!(!found("https://karkinos.example/user/corro"));
+    assert!(!found("ferris"));
+}
@@ -0,0 +1,23 @@
+//! QR codes, for swapping details at meetups without spelling out names.
+
+use qrcode::{EcLevel, QrCode};
+use qrcode::render::svg;
+use qrcode::types::QrError;
+
+/// Draws a QR code holding the given text, as an SVG image.
+///
+/// Fails if the text is too long to fit in a QR code.
+pub fn svg(text: &str) -> Result<String, QrError> {
+    // Low error correction leaves the most room for long vCards
+    let code = QrCode::with_error_correction_level(text.as_bytes(), EcLevel::L)?;
+    Ok(code.render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .quiet_zone(true)
+        .build())
+}
+
+#[test]
+fn too_long() {
+    assert!(svg("https://karkinos.lambda.xyz/user/ferris").unwrap().starts_with("<?xml"));
+    assert_eq!(svg(&"x".repeat(5000)), Err(QrError::DataTooLong));
+}
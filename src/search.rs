@@ -59,6 +59,23 @@ impl<K: Clone + Ord> SearchIndex<K> {
         }
     }
 
+    /// Iterates over every word in the index, along with the keys it was
+    /// found under and their weights.
+    pub fn words<'a>(&'a self) -> impl Iterator<Item=(&'a str, &'a BTreeMap<K, u64>)> + 'a {
+        self.index.iter().map(|(word, keys)| (&word[..], keys))
+    }
+
+    /// Like `query`, but without spelling correction, so that every result
+    /// has a word starting with each word of the query.
+    pub fn query_prefix(&self, text: &str) -> Vec<(K, u64)> {
+        self.query_exact(&terms(text))
+    }
+
+    /// Returns the keys that a whole word was found under, and their weights.
+    pub fn get(&self, word: &str) -> Option<&BTreeMap<K, u64>> {
+        self.index.get(word)
+    }
+
     fn query_exact<S: AsRef<str>>(&self, words: &[S]) -> Vec<(K, u64)> {
         // Split text into words
         let mut results = words.iter()
@@ -112,6 +129,11 @@ impl<K: Clone + TrieKey, V> TrieExt<K, V> for Trie<K, V> {
     }
 }
 
+/// Splits text into words, normalized in the same way as the index.
+pub fn terms(text: &str) -> Vec<String> {
+    text.unicode_words().map(nfkd_case_fold).collect()
+}
+
 fn nfkd_case_fold(text: &str) -> String {
     text.nfd().default_case_fold().nfkd().default_case_fold().nfkd().collect()
 }
@@ -0,0 +1,126 @@
+//! Finds people who have something in common, to suggest on each other's
+//! profile pages.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use models::User;
+use search::{self, SearchIndex};
+
+/// How many people to suggest on each page.
+const SUGGESTIONS: usize = 5;
+
+/// Words or channels that more people than this have in common don't say
+/// much about any two of them, and would take a long time to count.
+const MAX_SHARED: usize = 150;
+
+/// Words shorter than this are mostly noise, like "a" or "in".
+const MIN_WORD_LEN: usize = 3;
+
+/// How much more a shared channel counts than a shared word.
+const CHANNEL_WEIGHT: f64 = 2.0;
+
+/// Works out who is most like each person, from the channels they're in and
+/// the words in their notes.
+///
+/// Each thing two people have in common counts for more the fewer other
+/// people have it, using the document frequencies in the search index.
+pub fn similar(data: &BTreeMap<String, Result<User, String>>, index: &SearchIndex<String>)
+    -> BTreeMap<String, Vec<String>>
+{
+    let total = data.len() as f64;
+    let mut words: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    let mut channels: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for (id, user) in data {
+        let user = match *user {
+            Ok(ref user) => user,
+            Err(_) => continue,
+        };
+        if let Some(ref notes) = user.notes {
+            let terms: BTreeSet<String> = search::terms(notes).into_iter()
+                .filter(|term| term.chars().count() >= MIN_WORD_LEN)
+                .filter(|term| !term.chars().all(|c| c.is_numeric()))
+                .collect();
+            for term in terms {
+                words.entry(term).or_insert_with(Vec::new).push(id);
+            }
+        }
+        let joined: BTreeSet<String> = user.irc_channels.iter().map(|c| c.to_lowercase()).collect();
+        for channel in joined {
+            channels.entry(channel).or_insert_with(Vec::new).push(id);
+        }
+    }
+
+    let mut scores: HashMap<(&str, &str), f64> = HashMap::new();
+    {
+        let mut add = |members: &[&str], weight: f64| {
+            if members.len() < 2 || members.len() > MAX_SHARED {
+                return;
+            }
+            for (i, &a) in members.iter().enumerate() {
+                for &b in &members[i + 1..] {
+                    *scores.entry((a, b)).or_insert(0.0) += weight;
+                }
+            }
+        };
+        for (word, members) in &words {
+            // The index has seen the word everywhere, not just in notes
+            let found_in = index.get(word).map_or(members.len(), |keys| keys.len());
+            add(members, (total / found_in as f64).ln());
+        }
+        for members in channels.values() {
+            add(members, CHANNEL_WEIGHT * (total / members.len() as f64).ln());
+        }
+    }
+
+    let mut candidates: BTreeMap<&str, Vec<(&str, f64)>> = BTreeMap::new();
+    for (&(a, b), &score) in &scores {
+        if score > 0.0 {
+            candidates.entry(a).or_insert_with(Vec::new).push((b, score));
+            candidates.entry(b).or_insert_with(Vec::new).push((a, score));
+        }
+    }
+    candidates.into_iter()
+        .map(|(id, mut others)| {
+            // Break ties by id, so that the suggestions don't change between reloads
+            others.sort_by(|&(a, x), &(b, y)| {
+                y.partial_cmp(&x).unwrap_or(Ordering::Equal).then(a.cmp(b))
+            });
+            let others = others.into_iter()
+                .take(SUGGESTIONS)
+                .map(|(other, _)| other.to_string())
+                .collect();
+            (id.to_string(), others)
+        })
+        .collect()
+}
+
+#[test]
+fn suggestions() {
+    use serde_json;
+
+    let mut data: BTreeMap<String, Result<User, String>> = BTreeMap::new();
+    {
+        let mut add = |id: &str, json: &str| {
+            let user = User::from_value(serde_json::from_str(json).unwrap()).unwrap();
+            data.insert(id.to_string(), Ok(user));
+        };
+        add("ferris", r#"{ "irc_channels": ["rust-embedded"], "notes": "Blinking LEDs" }"#);
+        add("corro", r#"{ "irc_channels": ["rust-embedded"], "notes": "Unsafe code" }"#);
+        add("crab", r#"{ "irc_channels": ["rust"], "notes": "Writing unsafe code, carefully" }"#);
+        add("lobster", r#"{ "irc_channels": ["rust"], "notes": "Web servers" }"#);
+        add("shrimp", r#"{ "notes": "Games" }"#);
+    }
+    let mut index = SearchIndex::new();
+    for (id, user) in &data {
+        if let Ok(ref user) = *user {
+            index.add(id.clone(), user.notes.as_ref().unwrap(), 1);
+        }
+    }
+
+    let found = similar(&data, &index);
+    assert_eq!(found["ferris"], vec!["corro".to_string()]);
+    assert_eq!(found["corro"], vec!["crab".to_string(), "ferris".to_string()]);
+    assert_eq!(found["lobster"], vec!["crab".to_string()]);
+    assert!(!found.contains_key("shrimp"));
+}
@@ -0,0 +1,256 @@
+//! Renders the whole site to static files, for hosting a snapshot without
+//! running the server.
+
+use iron::url::form_urlencoded;
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use avatar;
+use history::History;
+use models::{SortOrder, User, Users};
+use qr;
+use update::UPSTREAM_URL;
+use vcard;
+use views::{self, Urls};
+
+/// Where the pages link to, relative to the page being rendered, so that the
+/// snapshot can be browsed straight off the disk.
+struct StaticUrls<'a> {
+    /// The path of the page being rendered, from the top of the site.
+    path: String,
+    /// The address of the live site, for links that have to be absolute.
+    base_url: &'a str,
+}
+
+impl<'a> StaticUrls<'a> {
+    fn new(path: &str, base_url: &'a str) -> StaticUrls<'a> {
+        StaticUrls { path: path.to_string(), base_url: base_url }
+    }
+
+    /// Returns the way back to the top of the site from the current page.
+    fn root(&self) -> String {
+        "../".repeat(self.path.matches('/').count())
+    }
+}
+
+impl<'a> Urls for StaticUrls<'a> {
+    /// Returns where the page being rendered lives on the live site, since
+    /// it's used for canonical links and there's no telling where the
+    /// snapshot will be hosted.
+    fn current(&self) -> String {
+        let path = self.path.trim_right_matches(".html");
+        let path = if path == "index" { "" } else { path };
+        format!("{}/{}", self.base_url, path)
+    }
+
+    fn route(&self, route: &str, params: &[(&str, &str)]) -> String {
+        let param = |name: &str| {
+            params.iter().find(|&&(key, _)| key == name).map_or("", |&(_, value)| value)
+        };
+        let mut query = Vec::new();
+        let path = match route {
+            "home" => "index.html".to_string(),
+            "user" if param("id").ends_with(".vcf") => format!("user/{}", param("id")),
+            "user" => format!("user/{}.html", param("id")),
+            "user_qr" if param("of") == "vcard" => format!("user/{}/qr-vcard.svg", param("id")),
+            "user_qr" => format!("user/{}/qr.svg", param("id")),
+            "user_badge" => format!("user/{}/badge.svg", param("id")),
+            "embed_user" => format!("embed/user/{}.html", param("id")),
+            "user_neighbourhood" => format!("user/{}/neighbourhood.html", param("id")),
+            "channel" => format!("channel/{}.html", file_name(param("name"))),
+            "channels_svg" => "channels.svg".to_string(),
+            // Snapshots don't fetch anyone's picture, so these are all identicons
+            "avatar" => format!("avatar/{}.svg", param("id")),
+            "static" => format!("static/{}", param("path")),
+            // There's no history in a snapshot, so link to the upstream repo
+            "user_history" =>
+                return format!("{}/commits/master/data/{}.json", UPSTREAM_URL, param("id")),
+            "feed" => return format!("{}/commits/master.atom", UPSTREAM_URL),
+            // Nor is there a random page, so offer the whole list instead
+            "random" => "users.html".to_string(),
+            _ => {
+                query.extend(params.iter().cloned());
+                format!("{}.html", route)
+            },
+        };
+        let mut url = self.root() + &path;
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(query)
+                .finish());
+        }
+        url
+    }
+}
+
+/// Renders every page into the given directory, along with the files in
+/// `static_dir`.
+///
+/// `base_url` is the address of the live site, for canonical links and the
+/// vCards.
+pub fn build(
+    out_dir: &Path, static_dir: &Path, users: &Users, history: &History, base_url: &str)
+    -> io::Result<()>
+{
+    let site = Site { out_dir: out_dir, base_url: base_url };
+    let profile_url = |id: &str| format!("{}/user/{}", base_url, id);
+
+    fs::create_dir_all(out_dir.join("user"))?;
+    fs::create_dir_all(out_dir.join("channel"))?;
+    fs::create_dir_all(out_dir.join("avatar"))?;
+    fs::create_dir_all(out_dir.join("embed/user"))?;
+    fs::create_dir_all(out_dir.join("static"))?;
+
+    site.write("index.html", |r| views::home(r).into_string())?;
+    site.write("404.html", |r| views::not_found(r).into_string())?;
+    site.write("search.html", |r| views::static_search(r).into_string())?;
+
+    let channels = users.channels();
+    let graph = users.channel_graph();
+
+    for (id, user) in users.iter() {
+        File::create(out_dir.join("avatar").join(format!("{}.svg", id)))?
+            .write_all(&avatar::identicon(id).body)?;
+        let path = format!("user/{}.html", id);
+        match user {
+            Ok(user) => {
+                site.write(&path, |r| {
+                    let revisions = history.revisions(id);
+                    views::user(r, id, user, users, revisions, false, false).into_string()
+                })?;
+                let card = vcard::vcard(id, user, &profile_url(id));
+                fs::create_dir_all(out_dir.join("user").join(id))?;
+                let profile_qr = qr::svg(&profile_url(id)).map_err(|e| {
+                    let message = format!("could not draw QR code for {}: {}", id, e);
+                    io::Error::new(io::ErrorKind::InvalidData, message)
+                })?;
+                // A long vCard might not fit, but the address always will
+                let vcard_qr = qr::svg(&card).unwrap_or_else(|_| profile_qr.clone());
+                site.write(&format!("user/{}/qr.svg", id), |_| profile_qr)?;
+                site.write(&format!("user/{}/qr-vcard.svg", id), |_| vcard_qr)?;
+                site.write(&format!("user/{}.vcf", id), |_| card)?;
+                site.write(&format!("user/{}/badge.svg", id), |_| views::badge(id))?;
+                site.write(&format!("embed/user/{}.html", id), |r| {
+                    views::embed(r, id, user, users.mentions(id)).into_string()
+                })?;
+                if !user.irc_channels.is_empty() {
+                    let neighbourhood = graph.neighbourhood(&user.irc_channels);
+                    site.write(&format!("user/{}/neighbourhood.html", id), |r| {
+                        views::neighbourhood(r, id, user, &neighbourhood).into_string()
+                    })?;
+                }
+            },
+            Err(error) =>
+                site.write(&path, |r| views::user_error(r, id, error).into_string())?,
+        }
+    }
+
+    let entries = users.sorted(SortOrder::Id);
+    site.write("users.html", |r| {
+        views::directory(r, &entries, SortOrder::Id, None).into_string()
+    })?;
+
+    site.write("channels.html", |r| views::channels(r, channels, graph).into_string())?;
+    site.write("channels.svg", |r| views::channel_graph(r, graph, &[]).into_string())?;
+    for (name, members) in channels {
+        let members: Vec<(&str, &User)> = members.iter()
+            .filter_map(|id| users.get(id).and_then(Result::ok).map(|user| (&id[..], user)))
+            .collect();
+        let overlapping = graph.overlapping(name);
+        site.write(&format!("channel/{}.html", file_name(name)),
+                   |r| views::channel(r, name, &members, &overlapping).into_string())?;
+    }
+
+    for entry in fs::read_dir(static_dir)? {
+        let entry = entry?;
+        fs::copy(entry.path(), out_dir.join("static").join(entry.file_name()))?;
+    }
+    write_search_index(&mut File::create(out_dir.join("static/search-index.js"))?, users)?;
+
+    Ok(())
+}
+
+struct Site<'a> {
+    out_dir: &'a Path,
+    base_url: &'a str,
+}
+
+impl<'a> Site<'a> {
+    fn write<F>(&self, path: &str, render: F) -> io::Result<()> where
+        F: FnOnce(&StaticUrls) -> String
+    {
+        let body = render(&StaticUrls::new(path, self.base_url));
+        File::create(self.out_dir.join(path))?.write_all(body.as_bytes())
+    }
+}
+
+/// Turns a name from the data into something safe to use as a file name, by
+/// writing any byte other than `[a-z0-9_-]` as `~` and two hex digits.
+fn file_name(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    for &b in name.as_bytes() {
+        match b {
+            b'a'...b'z' | b'0'...b'9' | b'_' | b'-' => result.push(b as char),
+            _ => result.push_str(&format!("~{:02x}", b)),
+        }
+    }
+    result
+}
+
+/// The search index, in a form that `static/search.js` can use.
+#[derive(Serialize)]
+struct ClientIndex<'a> {
+    /// The id and display name of each entry.
+    users: Vec<(&'a str, String)>,
+    /// Maps each word to the entries it appears in (as indices into `users`)
+    /// and how much weight it has there.
+    words: BTreeMap<&'a str, Vec<(usize, u64)>>,
+}
+
+/// Writes the search index as a script, so that it can be loaded even when
+/// the snapshot is opened from disk.
+fn write_search_index<W: Write>(out: &mut W, users: &Users) -> io::Result<()> {
+    let ids: Vec<&str> = users.iter()
+        .filter(|&(_, ref user)| user.is_ok())
+        .map(|(id, _)| id)
+        .collect();
+    let positions: BTreeMap<&str, usize> = ids.iter()
+        .enumerate()
+        .map(|(i, &id)| (id, i))
+        .collect();
+    let index = ClientIndex {
+        users: ids.iter()
+            .map(|&id| {
+                let name = match users.get(id) {
+                    Some(Ok(&User { name: Some(ref name), .. })) => format!("{} ({})", name, id),
+                    _ => id.to_string(),
+                };
+                (id, name)
+            })
+            .collect(),
+        words: users.search_index().words()
+            .map(|(word, keys)| {
+                let keys = keys.iter()
+                    .filter_map(|(id, &weight)| positions.get(&id[..]).map(|&i| (i, weight)))
+                    .collect();
+                (word, keys)
+            })
+            .collect(),
+    };
+    write!(out, "var searchIndex = ")?;
+    serde_json::to_writer(&mut *out, &index)?;
+    writeln!(out, ";")
+}
+
+#[test]
+fn file_names() {
+    assert_eq!(file_name("rust-beginners"), "rust-beginners");
+    assert_eq!(file_name("../etc/passwd"), "~2e~2e~2fetc~2fpasswd");
+    assert_eq!(file_name("rust.de"), "rust~2ede");
+    let urls = StaticUrls::new("user/ferris.html", "https://karkinos.example");
+    assert_eq!(urls.route("channel", &[("name", "a/b")]), "../channel/a~2fb.html");
+}
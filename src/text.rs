@@ -0,0 +1,301 @@
+//! Plain text and Markdown versions of the user and search pages, for
+//! terminals and other places where HTML would get in the way.
+
+use iron::headers::{q, Accept, QualityItem, UserAgent};
+use iron::mime::{Mime, SubLevel, TopLevel};
+use iron::prelude::*;
+use std::fmt::Write;
+
+use models::{Service, User};
+
+/// How wide the plain text output is allowed to get.
+const WIDTH: usize = 72;
+
+/// A non-HTML format that pages can be rendered in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flavor {
+    Plain,
+    Markdown,
+}
+
+impl Flavor {
+    pub fn from_extension(extension: &str) -> Option<Flavor> {
+        match extension {
+            "txt" => Some(Flavor::Plain),
+            "md" => Some(Flavor::Markdown),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Flavor::Plain => "text/plain; charset=utf-8",
+            Flavor::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+
+    /// Picks a flavor based on the request headers, or `None` for HTML.
+    pub fn negotiate(r: &Request) -> Option<Flavor> {
+        let mut best = None;
+        if let Some(&Accept(ref items)) = r.headers.get::<Accept>() {
+            for &QualityItem { ref item, quality } in items {
+                let flavor = match *item {
+                    Mime(TopLevel::Text, SubLevel::Html, _) => None,
+                    Mime(TopLevel::Text, SubLevel::Plain, _) => Some(Flavor::Plain),
+                    Mime(TopLevel::Text, SubLevel::Ext(ref sub), _) if sub == "markdown" =>
+                        Some(Flavor::Markdown),
+                    _ => continue,
+                };
+                // On a tie, the first one listed wins
+                if quality > q(0) && best.map_or(true, |(best_quality, _)| quality > best_quality) {
+                    best = Some((quality, flavor));
+                }
+            }
+        }
+        match best {
+            Some((_, flavor)) => flavor,
+            // Command line tools only ask for `*/*`, but would rather not
+            // get HTML
+            None if is_command_line(r) => Some(Flavor::Plain),
+            None => None,
+        }
+    }
+}
+
+fn is_command_line(r: &Request) -> bool {
+    match r.headers.get::<UserAgent>() {
+        Some(&UserAgent(ref agent)) =>
+            ["curl/", "Wget/", "HTTPie/"].iter().any(|prefix| agent.starts_with(prefix)),
+        None => false,
+    }
+}
+
+/// Renders a user's details.
+///
+/// `profile_url` gives the address of a user's page on the web site, since
+/// this isn't always called from a web request.
+pub fn user(profile_url: &Fn(&str) -> String, flavor: Flavor, id: &str, user: &User) -> String {
+    let mut out = String::new();
+    heading(&mut out, flavor, &user_title(id, Some(user)));
+    user_box(&mut out, profile_url, flavor, id, user);
+    out
+}
+
+pub fn user_error(flavor: Flavor, id: &str, error: &str) -> String {
+    let mut out = String::new();
+    heading(&mut out, flavor, id);
+    paragraph(&mut out, flavor, &format!(
+        "The user {} exists, but their entry could not be parsed. (Error: {})", id, error));
+    out
+}
+
+pub fn user_not_found(flavor: Flavor, id: &str) -> String {
+    let mut out = String::new();
+    heading(&mut out, flavor, id);
+    paragraph(&mut out, flavor, &format!("The user {} could not be found.", id));
+    out
+}
+
+/// Explains how to search, for when no query was given. `path` is where
+/// the search was asked for.
+pub fn search_usage(flavor: Flavor, path: &str) -> String {
+    let mut out = String::new();
+    heading(&mut out, flavor, "Search");
+    paragraph(&mut out, flavor, &format!(
+        "Add a query to the address to search, for example {}?q=ferris", path));
+    out
+}
+
+pub fn search_results<'u, I>(
+    profile_url: &Fn(&str) -> String, flavor: Flavor, query: &str, results: I,
+    correction: Option<String>) -> String where
+    I: Iterator<Item=(Result<&'u User, &'u str>, String, u64)>,
+{
+    let mut out = String::new();
+    heading(&mut out, flavor, &format!("Search results for “{}”", query));
+    if let Some(correction) = correction {
+        paragraph(&mut out, flavor, &format!("Showing results for “{}”", correction));
+    }
+    let mut empty = true;
+    for (user, id, _) in results {
+        empty = false;
+        let title = user_title(&id, user.ok());
+        match flavor {
+            Flavor::Plain => {
+                writeln!(out, "{}", title).unwrap();
+                writeln!(out, "{}", "-".repeat(title.chars().count())).unwrap();
+            },
+            Flavor::Markdown => writeln!(out, "## {}\n", escape_markdown(&title)).unwrap(),
+        }
+        if let Ok(user) = user {
+            user_box(&mut out, profile_url, flavor, &id, user);
+        } else {
+            writeln!(out).unwrap();
+        }
+    }
+    if empty {
+        paragraph(&mut out, flavor, "No results found.");
+    }
+    out
+}
+
+fn user_title(id: &str, user: Option<&User>) -> String {
+    if let Some(name) = user.and_then(|user| user.name.as_ref()) {
+        format!("{} ({})", name, id)
+    } else {
+        id.to_string()
+    }
+}
+
+fn heading(out: &mut String, flavor: Flavor, title: &str) {
+    match flavor {
+        Flavor::Plain => {
+            writeln!(out, "{}", title).unwrap();
+            writeln!(out, "{}\n", "=".repeat(title.chars().count())).unwrap();
+        },
+        Flavor::Markdown => writeln!(out, "# {}\n", escape_markdown(title)).unwrap(),
+    }
+}
+
+fn paragraph(out: &mut String, flavor: Flavor, text: &str) {
+    match flavor {
+        Flavor::Plain => out.push_str(&wrap(text, WIDTH)),
+        Flavor::Markdown => out.push_str(&escape_markdown(text)),
+    }
+    out.push_str("\n\n");
+}
+
+/// Lists a user's details as a label, some text, and maybe a link, in the
+/// same order as `views::user_box`.
+pub fn details(id: &str, user: &User) -> Vec<(String, String, Option<String>)> {
+    let mut rows = Vec::new();
+    rows.push(("GitHub".to_string(), id.to_string(), Some(Service::GitHub.url(id))));
+    if let Some(ref nick) = user.irc {
+        let mut text = nick.clone();
+        if !user.irc_channels.is_empty() {
+            let channels: Vec<String> = user.irc_channels.iter()
+                .map(|channel| format!("#{}", channel))
+                .collect();
+            text.push_str(" on ");
+            text.push_str(&channels.join(", "));
+        }
+        rows.push(("IRC".to_string(), text, None));
+    }
+    if let Some(ref x) = user.discourse {
+        rows.push(("Discourse".to_string(), x.clone(), Some(Service::Discourse.url(x))));
+    }
+    if let Some(ref x) = user.reddit {
+        rows.push(("Reddit".to_string(), x.clone(), Some(Service::Reddit.url(x))));
+    }
+    if let Some(ref x) = user.twitter {
+        rows.push(("Twitter".to_string(), x.clone(), Some(Service::Twitter.url(x))));
+    }
+    if let Some(ref x) = user.website {
+        rows.push(("Website".to_string(), x.clone(), Some(x.clone())));
+    }
+    if let Some(ref x) = user.blog {
+        rows.push(("Blog".to_string(), x.clone(), Some(x.clone())));
+    }
+    if let Some(ref x) = user.email {
+        rows.push(("Email".to_string(), x.clone(), None));
+    }
+    for (key, value) in &user.extra {
+        let text = value.as_str().map_or_else(|| value.to_string(), str::to_string);
+        rows.push((key.clone(), text, None));
+    }
+    rows
+}
+
+/// Renders the details of a user, like `views::user_box`.
+fn user_box(
+    out: &mut String, profile_url: &Fn(&str) -> String, flavor: Flavor, id: &str, user: &User)
+{
+    let rows = details(id, user);
+    let profile_url = profile_url(id);
+    match flavor {
+        Flavor::Plain => {
+            let label_width = rows.iter().map(|row| row.0.chars().count()).max().unwrap_or(0);
+            for (label, text, link) in rows {
+                writeln!(out, "{:width$}  {}", label, link.unwrap_or(text), width = label_width).unwrap();
+            }
+            if let Some(ref notes) = user.notes {
+                writeln!(out).unwrap();
+                for paragraph in notes.split("\n\n") {
+                    writeln!(out, "{}\n", wrap(paragraph, WIDTH)).unwrap();
+                }
+            } else {
+                writeln!(out).unwrap();
+            }
+            writeln!(out, "{}\n", profile_url).unwrap();
+        },
+        Flavor::Markdown => {
+            for (label, text, link) in rows {
+                match link {
+                    Some(link) =>
+                        writeln!(out, "- **{}:** [{}](<{}>)", escape_markdown(&label),
+                            escape_markdown(&text), link).unwrap(),
+                    None => writeln!(out, "- **{}:** {}", escape_markdown(&label),
+                        escape_markdown(&text)).unwrap(),
+                }
+            }
+            writeln!(out).unwrap();
+            // The notes are Markdown already
+            if let Some(ref notes) = user.notes {
+                writeln!(out, "{}\n", notes.trim()).unwrap();
+            }
+            writeln!(out, "[Profile on Karkinos](<{}>)\n", profile_url).unwrap();
+        },
+    }
+}
+
+/// Drops control characters other than line breaks and tabs, so that the
+/// data can't send escape sequences to someone's terminal.
+pub fn printable(text: &str) -> String {
+    text.chars().filter(|&c| c == '\n' || c == '\t' || !c.is_control()).collect()
+}
+
+/// Wraps text to the given width, collapsing whitespace along the way.
+fn wrap(text: &str, width: usize) -> String {
+    let mut result = String::new();
+    let mut line_width = 0;
+    for word in text.split_whitespace() {
+        let word_width = word.chars().count();
+        if line_width > 0 && line_width + 1 + word_width > width {
+            result.push('\n');
+            line_width = 0;
+        } else if line_width > 0 {
+            result.push(' ');
+            line_width += 1;
+        }
+        result.push_str(word);
+        line_width += word_width;
+    }
+    result
+}
+
+/// Escapes characters that would otherwise be taken as Markdown syntax.
+fn escape_markdown(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\`*_[]<>#|".contains(c) {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+#[test]
+fn printable_text() {
+    assert_eq!(printable("Ferris\x1b[2J\r\n\tcrab\u{9b}"), "Ferris[2J\n\tcrab");
+}
+
+#[test]
+fn wrapping() {
+    let text = "The quick brown fox jumps over the lazy dog. ".repeat(5);
+    let wrapped = wrap(&text, 20);
+    for line in wrapped.lines() {
+        assert!(line.chars().count() <= 20);
+    }
+    assert!(wrapped.starts_with("The quick brown fox\njumps over the lazy\n"));
+}
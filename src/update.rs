@@ -1,55 +1,402 @@
+use std::env;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
-const REPO_URL: &'static str = "https://github.com/nrc/rustaceans.org.git";
+/// The repository that the data is pulled from, which is also where its
+/// history can be browsed.
+pub const UPSTREAM_URL: &'static str = "https://github.com/nrc/rustaceans.org";
+
+/// Decides which upstream commits the updater is willing to apply.
+///
+/// We serve people's contact details, so a compromised data repository would
+/// be bad news. This lets us refuse updates that don't come from someone we
+/// trust.
+#[derive(Clone, Debug)]
+pub enum TrustPolicy {
+    /// Apply whatever upstream gives us.
+    Any,
+    /// Every new commit must carry a valid signature from one of these keys,
+    /// given as fingerprints.
+    TrustedKeys {
+        keys: Vec<String>,
+        /// The GnuPG home directory holding the keys, if not the default.
+        gnupg_home: Option<PathBuf>,
+    },
+    /// Only update to the commit named by this ref.
+    PinnedRef(String),
+}
+
+impl TrustPolicy {
+    /// Reads the policy from the `KARKINOS_TRUSTED_KEYS` (a comma-separated
+    /// list of fingerprints) or `KARKINOS_PINNED_REF` environment variables.
+    pub fn from_env() -> TrustPolicy {
+        if let Ok(keys) = env::var("KARKINOS_TRUSTED_KEYS") {
+            let keys = keys.split(',')
+                .map(normalize_fingerprint)
+                .filter(|key| !key.is_empty())
+                .collect();
+            TrustPolicy::TrustedKeys { keys: keys, gnupg_home: None }
+        } else if let Ok(pinned) = env::var("KARKINOS_PINNED_REF") {
+            TrustPolicy::PinnedRef(pinned)
+        } else {
+            TrustPolicy::Any
+        }
+    }
+
+    /// Checks the commits between `HEAD` and `fetched`.
+    ///
+    /// Returns the reason for refusing them, or `None` if they can be applied.
+    fn check(&self, repo_dir: &Path, fetched: &str) -> io::Result<Option<String>> {
+        match *self {
+            TrustPolicy::Any => Ok(None),
+            TrustPolicy::TrustedKeys { keys: ref trusted, ref gnupg_home } => {
+                let Output { status, stdout, .. } = git()
+                    .arg("rev-list").arg(format!("HEAD..{}", fetched))
+                    .current_dir(repo_dir)
+                    .output()?;
+                if !status.success() {
+                    return Err(io::Error::new(io::ErrorKind::Other, "failed to list new commits"));
+                }
+                let gnupg_home = gnupg_home.as_ref().map(PathBuf::as_path);
+                for commit in String::from_utf8_lossy(&stdout).lines() {
+                    let keys = signing_keys(repo_dir, gnupg_home, commit)?;
+                    if !keys.iter().any(|key| trusted.contains(key)) {
+                        return Ok(Some(format!("commit {} is not signed by a trusted key", commit)));
+                    }
+                }
+                Ok(None)
+            },
+            TrustPolicy::PinnedRef(ref pinned) => {
+                let expected = rev_parse(repo_dir, &format!("{}^{{commit}}", pinned))?;
+                if expected == fetched {
+                    Ok(None)
+                } else {
+                    Ok(Some(format!("commit {} does not match pinned ref {} ({})",
+                                    fetched, pinned, expected)))
+                }
+            },
+        }
+    }
+}
 
 pub struct Updater {
+    repo_dir: PathBuf,
     data_dir: PathBuf,
+    status: Arc<Mutex<UpdateStatus>>,
+}
+
+/// What the updater knows about the commits it has seen so far.
+#[derive(Clone, Debug, Default)]
+pub struct UpdateStatus {
+    /// The most recent commit whose data was accepted.
+    pub last_good: Option<String>,
+    /// The most recent update that was rejected, if any.
+    pub rejected: Option<RejectedUpdate>,
+}
+
+/// An update that failed the safety checks, and was rolled back.
+#[derive(Clone, Debug)]
+pub struct RejectedUpdate {
+    pub commit: String,
+    pub reason: String,
+    /// The entries that failed to parse at that commit.
+    pub errors: Vec<(String, String)>,
+}
+
+/// Returns where `Updater::start` clones the data repository to, under the
+/// given directory.
+pub fn repo_dir(root: &Path) -> PathBuf {
+    root.join("data")
+}
+
+/// Returns where the entries are in the data repository.
+pub fn data_dir(repo_dir: &Path) -> PathBuf {
+    repo_dir.join("data")
 }
 
 impl Updater {
-    pub fn start<P: AsRef<Path>>(root: P) -> io::Result<Updater> {
+    /// Clones the data repository if needed, and starts pulling from it in
+    /// the background.
+    ///
+    /// The initial clone is trusted as-is; `policy` only applies to the
+    /// commits fetched after that.
+    pub fn start<P: AsRef<Path>>(root: P, policy: TrustPolicy) -> io::Result<Updater> {
         let Output { status, stdout, .. } = git().arg("--version").output()?;
         if !status.success() {
             return Err(io::Error::new(io::ErrorKind::Other, "failed to check git version"));
         }
         info!("found git: {}", String::from_utf8_lossy(&stdout).trim());
 
-        let repo_dir = root.as_ref().join("data");
+        let repo_dir = repo_dir(root.as_ref());
         if !repo_dir.is_dir() {
             // Clone the repo
             info!("cloning rustaceans data");
-            let status = git().arg("clone").arg(REPO_URL).arg(&repo_dir).status()?;
+            let status = git().arg("clone").arg(UPSTREAM_URL).arg(&repo_dir).status()?;
             if !status.success() {
                 return Err(io::Error::new(io::ErrorKind::Other, "failed to clone data repository"));
             }
         }
 
+        let status = Arc::new(Mutex::new(UpdateStatus::default()));
+
         {
             let repo_dir = repo_dir.clone();
+            let status = status.clone();
             thread::spawn(move || loop {
                 thread::sleep(Duration::from_secs(60 * 60));
                 info!("updating rustaceans data");
-                match git().arg("pull").arg("--ff-only").current_dir(&repo_dir).status() {
-                    Ok(status) if status.success() => {},
-                    Ok(status) => error!("update failed with exit status {}", status),
-                    Err(e) => error!("update failed with error: {}", e),
+                match pull(&repo_dir, &policy, &status) {
+                    Ok(()) => info!("updated successfully"),
+                    Err(e) => error!("update failed: {}", e),
                 }
-                info!("updated successfully");
             });
         }
 
-        Ok(Updater { data_dir: repo_dir.join("data") })
+        Ok(Updater {
+            data_dir: data_dir(&repo_dir),
+            repo_dir: repo_dir,
+            status: status,
+        })
+    }
+
+    pub fn repo_dir(&self) -> &Path {
+        &self.repo_dir
     }
 
     pub fn data_dir(&self) -> &Path {
         &self.data_dir
     }
+
+    /// Returns the commit that is currently checked out.
+    pub fn head(&self) -> io::Result<String> {
+        rev_parse(&self.repo_dir, "HEAD")
+    }
+
+    pub fn status(&self) -> UpdateStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Records that the data at the current commit passed the safety checks.
+    pub fn accept(&self) {
+        match self.head() {
+            Ok(commit) => self.status.lock().unwrap().last_good = Some(commit),
+            Err(e) => error!("could not read current commit: {}", e),
+        }
+    }
+
+    /// Records that the data at the current commit failed the safety checks,
+    /// and pins the repository to the last commit that passed them.
+    ///
+    /// Later updates will skip over the rejected commit, but will pick up any
+    /// commits made on top of it.
+    pub fn reject(&self, reason: String, errors: Vec<(String, String)>) {
+        let commit = match self.head() {
+            Ok(commit) => commit,
+            Err(e) => {
+                error!("could not read current commit: {}", e);
+                return;
+            },
+        };
+        let mut status = self.status.lock().unwrap();
+        if let Some(ref last_good) = status.last_good {
+            if *last_good != commit {
+                warn!("rolling back data from {} to {}", commit, last_good);
+                let result = run(
+                    git().arg("reset").arg("--hard").arg(last_good).current_dir(&self.repo_dir),
+                    "failed to roll back data repository");
+                if let Err(e) = result {
+                    error!("{}", e);
+                }
+            }
+        }
+        status.rejected = Some(RejectedUpdate {
+            commit: commit,
+            reason: reason,
+            errors: errors,
+        });
+    }
+}
+
+/// Fetches new commits from upstream, and fast-forwards to them unless they
+/// have been rejected before or are not trusted.
+fn pull(repo_dir: &Path, policy: &TrustPolicy, status: &Mutex<UpdateStatus>) -> io::Result<()> {
+    run(git().arg("fetch").arg("origin").current_dir(repo_dir), "failed to fetch updates")?;
+    let fetched = rev_parse(repo_dir, "FETCH_HEAD")?;
+    if let Some(ref rejected) = status.lock().unwrap().rejected {
+        if rejected.commit == fetched {
+            info!("skipping commit {}, which was rejected before", fetched);
+            return Ok(());
+        }
+    }
+    if let Some(reason) = policy.check(repo_dir, &fetched)? {
+        error!("refusing unverified update: {}", reason);
+        status.lock().unwrap().rejected = Some(RejectedUpdate {
+            commit: fetched,
+            reason: reason,
+            errors: Vec::new(),
+        });
+        return Ok(());
+    }
+    run(git().arg("merge").arg("--ff-only").arg(&fetched).current_dir(repo_dir),
+        "failed to merge updates")
+}
+
+/// Returns the fingerprints of the keys that made valid signatures on the
+/// given commit.
+fn signing_keys(repo_dir: &Path, gnupg_home: Option<&Path>, commit: &str)
+    -> io::Result<Vec<String>>
+{
+    let mut command = git();
+    command.arg("verify-commit").arg("--raw").arg(commit).current_dir(repo_dir);
+    if let Some(gnupg_home) = gnupg_home {
+        // Git passes this on to GnuPG
+        command.env("GNUPGHOME", gnupg_home);
+    }
+    // An unsigned commit makes `git verify-commit` fail, but that just means
+    // there are no keys to report
+    let Output { stderr, .. } = command.output()?;
+    Ok(parse_valid_signatures(&String::from_utf8_lossy(&stderr)))
+}
+
+/// Extracts key fingerprints from the `VALIDSIG` lines of GnuPG status output.
+///
+/// Both the signing subkey and its primary key are returned. GnuPG reports
+/// signatures from expired or revoked keys as valid too, so nothing is
+/// returned unless there's a `GOODSIG`, and nothing at all if any signature
+/// is bad, expired, revoked or couldn't be checked.
+fn parse_valid_signatures(output: &str) -> Vec<String> {
+    let mut keys = Vec::new();
+    let mut good = false;
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 2 || fields[0] != "[GNUPG:]" {
+            continue;
+        }
+        match fields[1] {
+            "GOODSIG" => good = true,
+            "BADSIG" | "ERRSIG" | "EXPSIG" | "EXPKEYSIG" | "REVKEYSIG" => return Vec::new(),
+            "VALIDSIG" if fields.len() > 2 => {
+                keys.push(normalize_fingerprint(fields[2]));
+                if let Some(primary) = fields.get(11) {
+                    keys.push(normalize_fingerprint(primary));
+                }
+            },
+            _ => {},
+        }
+    }
+    if good { keys } else { Vec::new() }
+}
+
+fn normalize_fingerprint(key: &str) -> String {
+    key.chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_uppercase)
+        .collect()
+}
+
+fn rev_parse(repo_dir: &Path, rev: &str) -> io::Result<String> {
+    let Output { status, stdout, .. } = git()
+        .arg("rev-parse").arg("--verify").arg(rev)
+        .current_dir(repo_dir)
+        .output()?;
+    if !status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("failed to resolve {}", rev)));
+    }
+    Ok(String::from_utf8_lossy(&stdout).trim().to_string())
+}
+
+fn run(command: &mut Command, message: &str) -> io::Result<()> {
+    let status = command.status()?;
+    if !status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("{} ({})", message, status)));
+    }
+    Ok(())
 }
 
 fn git() -> Command {
     Command::new("git")
 }
+
+#[test]
+fn valid_signatures() {
+    const OUTPUT: &'static str = "\
+[GNUPG:] NEWSIG
+[GNUPG:] KEY_CONSIDERED 0123456789ABCDEF0123456789ABCDEF01234567 0
+[GNUPG:] GOODSIG 89ABCDEF01234567 Bors <bors@rust-lang.org>
+[GNUPG:] VALIDSIG FEDCBA9876543210FEDCBA9876543210FEDCBA98 2018-06-01 1527811200 0 4 0 1 8 00 0123456789abcdef0123456789abcdef01234567
+[GNUPG:] TRUST_UNDEFINED 0 pgp
+";
+    assert_eq!(parse_valid_signatures(OUTPUT), vec![
+        "FEDCBA9876543210FEDCBA9876543210FEDCBA98".to_string(),
+        "0123456789ABCDEF0123456789ABCDEF01234567".to_string(),
+    ]);
+    assert!(parse_valid_signatures("[GNUPG:] BADSIG 89ABCDEF01234567 Mallory").is_empty());
+    // GnuPG still says VALIDSIG when the key has expired or been revoked
+    let expired = OUTPUT.replace("GOODSIG", "EXPKEYSIG");
+    assert!(parse_valid_signatures(&expired).is_empty());
+    let revoked = OUTPUT.replace("GOODSIG", "REVKEYSIG");
+    assert!(parse_valid_signatures(&revoked).is_empty());
+    let validsig = OUTPUT.lines().find(|line| line.contains("VALIDSIG")).unwrap();
+    assert!(parse_valid_signatures(validsig).is_empty());
+}
+
+#[test]
+fn trusted_keys() {
+    use models::temp_dir;
+    use std::fs;
+
+    let dir = temp_dir("trusted-keys");
+    let gnupg_home = dir.join("gnupg");
+    let repo_dir = dir.join("repo");
+    fs::create_dir(&gnupg_home).unwrap();
+    fs::create_dir(&repo_dir).unwrap();
+    let gpg = |args: &[&str]| {
+        let Output { status, stdout, .. } = Command::new("gpg")
+            .arg("--batch").arg("--homedir").arg(&gnupg_home)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(status.success());
+        String::from_utf8_lossy(&stdout).into_owned()
+    };
+    gpg(&["--passphrase", "", "--quick-gen-key", "Ferris <ferris@example.com>",
+          "ed25519", "sign", "never"]);
+    let key = gpg(&["--with-colons", "--list-secret-keys"]).lines()
+        .find(|line| line.starts_with("fpr:"))
+        .and_then(|line| line.split(':').nth(9))
+        .unwrap()
+        .to_string();
+
+    let commit = |extra: &[&str]| {
+        // Keep the throwaway key out of the real keyring
+        run(git().env("GNUPGHOME", &gnupg_home)
+                .arg("-c").arg("user.name=Ferris").arg("-c").arg("user.email=ferris@example.com")
+                .arg("-c").arg(format!("user.signingkey={}", key))
+                .arg("commit").arg("--quiet").arg("--allow-empty").arg("--message=update")
+                .args(extra)
+                .current_dir(&repo_dir),
+            "failed to commit").unwrap();
+        rev_parse(&repo_dir, "HEAD").unwrap()
+    };
+    run(git().arg("init").arg("--quiet").current_dir(&repo_dir), "failed to init").unwrap();
+    let base = commit(&[]);
+    let signed = commit(&["--gpg-sign"]);
+    let unsigned = commit(&[]);
+    run(git().arg("checkout").arg("--quiet").arg(&base).current_dir(&repo_dir),
+        "failed to check out").unwrap();
+
+    let policy = |key: &str| TrustPolicy::TrustedKeys {
+        keys: vec![key.to_string()],
+        gnupg_home: Some(gnupg_home.clone()),
+    };
+    let trusted = policy(&key);
+    assert_eq!(trusted.check(&repo_dir, &signed).unwrap(), None);
+    // Every new commit has to be signed, not just the last one
+    assert!(trusted.check(&repo_dir, &unsigned).unwrap().is_some());
+    let other = policy("0123456789ABCDEF0123456789ABCDEF01234567");
+    assert!(other.check(&repo_dir, &signed).unwrap().is_some());
+}
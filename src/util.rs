@@ -0,0 +1,28 @@
+//! Small helpers shared by modules that write markup by hand.
+
+use std::fmt::{self, Display, Formatter, Write};
+
+/// Escapes text for use in XML content or attribute values.
+pub struct Escape<'a>(pub &'a str);
+
+impl<'a> Display for Escape<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '"' => f.write_str("&quot;")?,
+                '\'' => f.write_str("&apos;")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn escape() {
+    assert_eq!(Escape(r#"<a href="?x=1&y='2'">"#).to_string(),
+               "&lt;a href=&quot;?x=1&amp;y=&apos;2&apos;&quot;&gt;");
+}
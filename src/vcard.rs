@@ -0,0 +1,149 @@
+use models::{Service, User};
+
+/// A single property (content line) in a vCard.
+#[derive(Debug)]
+pub struct Property {
+    /// The property name, in upper case.
+    pub name: &'static str,
+    /// The `TYPE` parameter, if any.
+    pub kind: Option<&'static str>,
+    /// The value, before escaping.
+    pub value: String,
+    /// Whether the value is text, and so needs escaping. URIs are left alone.
+    is_text: bool,
+}
+
+impl Property {
+    pub fn text<S: Into<String>>(name: &'static str, value: S) -> Property {
+        Property { name: name, kind: None, value: value.into(), is_text: true }
+    }
+
+    /// Makes a URI property. URIs aren't escaped, so any control characters
+    /// are dropped, lest a line break in the data start a new property.
+    pub fn uri<S: Into<String>>(name: &'static str, kind: Option<&'static str>, value: S) -> Property {
+        let value = value.into().chars().filter(|c| !c.is_control()).collect();
+        Property { name: name, kind: kind, value: value, is_text: false }
+    }
+}
+
+/// Lists the properties of the vCard for the given user, except for
+/// `BEGIN`, `VERSION` and `END`.
+///
+/// `profile_url` should point back to their page on this site.
+pub fn properties(id: &str, user: &User, profile_url: &str) -> Vec<Property> {
+    let mut props = Vec::new();
+    props.push(Property::text("FN", user.name.as_ref().map_or(id, |name| &name[..])));
+    props.push(Property::text("NICKNAME", id));
+    props.push(Property::uri("UID", None, profile_url));
+    if let Some(ref email) = user.email {
+        props.push(Property::text("EMAIL", &email[..]));
+    }
+    props.push(Property::uri("URL", Some("profile"), profile_url));
+    props.push(Property::uri("URL", Some("github"), Service::GitHub.url(id)));
+    if let Some(ref website) = user.website {
+        props.push(Property::uri("URL", Some("home"), &website[..]));
+    }
+    if let Some(ref blog) = user.blog {
+        props.push(Property::uri("URL", Some("blog"), &blog[..]));
+    }
+    if let Some(ref x) = user.twitter {
+        props.push(Property::uri("URL", Some("twitter"), Service::Twitter.url(x)));
+    }
+    if let Some(ref x) = user.reddit {
+        props.push(Property::uri("URL", Some("reddit"), Service::Reddit.url(x)));
+    }
+    if let Some(ref x) = user.discourse {
+        props.push(Property::uri("URL", Some("discourse"), Service::Discourse.url(x)));
+    }
+    for channel in &user.irc_channels {
+        props.push(Property::text("CATEGORIES", &channel[..]));
+    }
+    if let Some(ref notes) = user.notes {
+        props.push(Property::text("NOTE", &notes[..]));
+    }
+    props
+}
+
+/// Renders a vCard (RFC 6350) for the given user.
+///
+/// `profile_url` should point back to their page on this site.
+pub fn vcard(id: &str, user: &User, profile_url: &str) -> String {
+    let mut card = VCard::new();
+    card.line("BEGIN", "VCARD");
+    card.line("VERSION", "4.0");
+    for prop in properties(id, user, profile_url) {
+        let name = match prop.kind {
+            Some(kind) => format!("{};TYPE={}", prop.name, kind),
+            None => prop.name.to_string(),
+        };
+        if prop.is_text {
+            card.line(&name, &escape(&prop.value));
+        } else {
+            card.line(&name, &prop.value);
+        }
+    }
+    card.line("END", "VCARD");
+    card.0
+}
+
+struct VCard(String);
+
+impl VCard {
+    fn new() -> VCard {
+        VCard(String::new())
+    }
+
+    /// Adds a content line, folding it so that no line is longer than 75
+    /// octets. Line breaks in the value are left out.
+    fn line(&mut self, name: &str, value: &str) {
+        let mut width = 0;
+        let value = value.chars().filter(|&c| c != '\r' && c != '\n');
+        for c in name.chars().chain(Some(':')).chain(value) {
+            if width + c.len_utf8() > 75 {
+                self.0.push_str("\r\n ");
+                width = 1;
+            }
+            self.0.push(c);
+            width += c.len_utf8();
+        }
+        self.0.push_str("\r\n");
+    }
+}
+
+/// Escapes a text value.
+fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            ',' => result.push_str("\\,"),
+            ';' => result.push_str("\\;"),
+            '\n' => result.push_str("\\n"),
+            '\r' => {},
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+#[test]
+fn folding() {
+    let mut card = VCard::new();
+    card.line("NOTE", &escape(&"ab, ".repeat(30)));
+    for line in card.0.split("\r\n") {
+        assert!(line.len() <= 75);
+    }
+    assert!(card.0.starts_with("NOTE:ab\\, ab\\, "));
+    assert!(card.0.contains("\r\n "));
+}
+
+#[test]
+fn uri_injection() {
+    use serde_json;
+
+    let json = r#"{ "website": "https://ferris.example/\r\nEMAIL:evil@example.org" }"#;
+    let user = User::from_value(serde_json::from_str(json).unwrap()).unwrap();
+    let card = vcard("ferris", &user, "https://karkinos.example/user/ferris");
+    assert!(card.contains("\r\nURL;TYPE=home:https://ferris.example/EMAIL:evil@example.org\r\n"));
+    assert!(!card.contains("\r\nEMAIL:"));
+}
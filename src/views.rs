@@ -3,16 +3,72 @@
 
 use ammonia;
 use iron::prelude::*;
+use router;
 use maud::{DOCTYPE, html, Markup, PreEscaped, Render};
-use pulldown_cmark::{self, Event, Parser, Tag};
+use pulldown_cmark::{self, Event, Tag};
+use serde_json;
 
-use models::User;
+use std::collections::BTreeMap;
 
-fn layout(r: &Request, title: Option<&str>, body: Markup) -> Markup {
-    layout_inner(r, title, title, body)
+use graph::ChannelGraph;
+use history::{FieldChange, Revision};
+use mention;
+use models::{Service, SortOrder, User, Users};
+use update::{UpdateStatus, UPSTREAM_URL};
+
+/// Where things are on the site, so that the same pages can be rendered in
+/// response to a request, or ahead of time by `site::build`.
+pub trait Urls {
+    /// Returns the address of the page being rendered.
+    fn current(&self) -> String;
+
+    /// Returns the address of the given route. Parameters that aren't part
+    /// of the route go in the query string.
+    fn route(&self, route: &str, params: &[(&str, &str)]) -> String;
+}
+
+impl<'a, 'b> Urls for Request<'a, 'b> {
+    fn current(&self) -> String {
+        self.url.to_string()
+    }
+
+    fn route(&self, route: &str, params: &[(&str, &str)]) -> String {
+        let params = params.iter()
+            .map(|&(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        router::url_for(self, route, params).to_string()
+    }
+}
+
+/// Like `url_for!`, but for any `Urls`.
+macro_rules! url {
+    ($urls:expr, $route:expr $(, $key:expr => $value:expr)*) => {
+        $urls.route($route, &[$(($key, &$value[..])),*])
+    };
 }
 
-fn layout_inner(r: &Request, head_title: Option<&str>, body_title: Option<&str>, body: Markup) -> Markup {
+/// What link previews (OpenGraph and Twitter cards) should show for a page.
+#[derive(Default)]
+struct Preview {
+    /// The OpenGraph type, if not `website`.
+    kind: Option<&'static str>,
+    description: Option<String>,
+    /// The Twitter handle of the person the page is about.
+    twitter: Option<String>,
+    /// A picture to show instead of the crab icon.
+    image: Option<String>,
+    /// Where to find out how to embed the page.
+    oembed: Option<String>,
+}
+
+fn layout(r: &Urls, title: Option<&str>, body: Markup) -> Markup {
+    layout_inner(r, title, title, Preview::default(), body)
+}
+
+fn layout_inner(
+    r: &Urls, head_title: Option<&str>, body_title: Option<&str>, preview: Preview, body: Markup)
+    -> Markup
+{
     html! {
         (DOCTYPE)
         html {
@@ -24,18 +80,43 @@ fn layout_inner(r: &Request, head_title: Option<&str>, body_title: Option<&str>,
                 "Karkinos"
             }
             meta name="viewport" content="width=device-width";
-            link rel="stylesheet" href=(url_for!(r, "static", "path" => "styles.css"));
-            link rel="icon" type="image/png" href=(url_for!(r, "static", "path" => "icon.png"));
+            link rel="stylesheet" href=(url!(r, "static", "path" => "styles.css"));
+            link rel="icon" type="image/png" href=(url!(r, "static", "path" => "icon.png"));
+            link rel="alternate" type="application/atom+xml" title="New and updated Rustaceans"
+                href=(url!(r, "feed"));
+            meta property="og:site_name" content="Karkinos";
+            meta property="og:type" content=(preview.kind.unwrap_or("website"));
+            meta property="og:title" content=(head_title.unwrap_or("Karkinos"));
+            meta property="og:url" content=(r.current());
+            @if let Some(ref image) = preview.image {
+                meta property="og:image" content=(image);
+                meta property="og:image:width" content="1200";
+                meta property="og:image:height" content="630";
+                meta name="twitter:card" content="summary_large_image";
+            } @else {
+                meta property="og:image" content=(url!(r, "static", "path" => "icon.png"));
+                meta name="twitter:card" content="summary";
+            }
+            @if let Some(ref description) = preview.description {
+                meta name="description" content=(description);
+                meta property="og:description" content=(description);
+            }
+            @if let Some(ref twitter) = preview.twitter {
+                meta name="twitter:creator" content={ "@" (twitter) };
+            }
+            @if let Some(ref oembed) = preview.oembed {
+                link rel="alternate" type="application/json+oembed" href=(oembed);
+            }
             body {
                 h1 {
-                    a href="/" {
+                    a href=(url!(r, "home")) {
                         span.thecrab { "🦀" }
                         "Karkinos"
                     }
                 }
                 @if let Some(body_title) = body_title {
                     h2 {
-                        a href=(r.url) title="Link to this page" {
+                        a href=(r.current()) title="Link to this page" {
                             (body_title)
                         }
                     }
@@ -46,12 +127,14 @@ fn layout_inner(r: &Request, head_title: Option<&str>, body_title: Option<&str>,
     }
 }
 
-pub fn home(r: &Request) -> Markup {
+pub fn home(r: &Urls) -> Markup {
     layout(r, None, html! {
         (search_form(r, ""))
         p {
             "… or view a "
-            a href=(url_for!(r, "random")) { "random Rustacean" }
+            a href=(url!(r, "random")) { "random Rustacean" }
+            ", or "
+            a href=(url!(r, "users")) { "browse them all" }
             "."
         }
         p {
@@ -96,9 +179,9 @@ pub fn home(r: &Request) -> Markup {
     })
 }
 
-fn search_form(r: &Request, value: &str) -> Markup {
+fn search_form(r: &Urls, value: &str) -> Markup {
     html! {
-        form action=(url_for!(r, "search")) {
+        form action=(url!(r, "search")) {
             input name="q" id="q" type="search" placeholder="Search"
                 autocomplete="off" value=(value);
         }
@@ -111,62 +194,273 @@ fn search_form(r: &Request, value: &str) -> Markup {
     }
 }
 
-pub fn not_found(r: &Request) -> Markup {
+pub fn not_found(r: &Urls) -> Markup {
     layout(r, Some("Not found"), html! {
         p {
             "The page at "
-            strong { (r.url) }
+            strong { (r.current()) }
             " could not be found."
         }
         p {
-            a href="/" { "‹ Back to home page" }
+            a href=(url!(r, "home")) { "‹ Back to home page" }
         }
     })
 }
 
-pub fn search(r: &Request) -> Markup {
+pub fn search(r: &Urls) -> Markup {
     layout(r, Some("Search"), html! {
         (search_form(r, ""))
     })
 }
 
 pub fn search_results<'u, I>(
-    r: &Request, query: &str, results: I, correction: Option<String>) -> Markup where
+    r: &Urls, users: &Users, query: &str, results: I, correction: Option<String>) -> Markup where
     I: Iterator<Item=(Result<&'u User, &'u str>, String, u64)>,
 {
     let title = format!("Search results for “{}”", query);
     let mut results = results.peekable();
-    layout_inner(r, Some(&title), None, html! {
+    layout_inner(r, Some(&title), None, Preview::default(), html! {
         (search_form(r, query))
         @if results.peek().is_none() {
             p { "No results found." }
-        } @else if let Some(correction) = correction {
-            p {
-                "Showing results for "
-                a href=(url_for!(r, "search", "q" => &correction[..])) {
-                    strong { (correction) }
+        } @else {
+            @if let Some(correction) = correction {
+                p {
+                    "Showing results for "
+                    a href=(url!(r, "search", "q" => &correction[..])) {
+                        strong { (correction) }
+                    }
                 }
             }
+            p.download {
+                a href=(url!(r, "vcards", "q" => query)) { "Download these results as vCards" }
+            }
         }
         @for (user, id, weight) in results {
             h3 title={ "Weight: " (weight) } {
-                a href=(url_for!(r, "user", "id" => &id[..])) {
+                a href=(url!(r, "user", "id" => &id[..])) {
                     (user_title(&id, user.ok()))
                 }
             }
             @if let Ok(user) = user {
-                (user_box(&id, user, 3))
+                (user_box(r, &id, user, users.mentions(&id), 3))
             }
             hr;
         }
     })
 }
 
-pub fn user(r: &Request, id: &str, user: &User) -> Markup {
-    layout(r, Some(&user_title(id, Some(user))), user_box(id, user, 2))
+/// `card` is whether a preview card can be drawn for them, rather than
+/// falling back to the icon, and `oembed` whether there's an oEmbed endpoint
+/// to advertise (there isn't in a snapshot).
+pub fn user(
+    r: &Urls, id: &str, user: &User, users: &Users, revisions: &[Revision], card: bool,
+    oembed: bool)
+    -> Markup
+{
+    let origins = users.origins(id);
+    let similar = users.similar(id);
+    let mentioned_by = users.mentioned_by(id);
+    let preview = Preview {
+        kind: Some("profile"),
+        description: Some(match user.notes {
+            Some(ref notes) => summarize(notes, 200),
+            None => format!("{} on Karkinos, a directory of Rust programmers", id),
+        }),
+        twitter: user.twitter.clone(),
+        image: if card { Some(url!(r, "user_card", "id" => id)) } else { None },
+        oembed: if oembed {
+            Some(url!(r, "oembed", "url" => url!(r, "user", "id" => id)))
+        } else {
+            None
+        },
+    };
+    let title = user_title(id, Some(user));
+    layout_inner(r, Some(&title), Some(&title), preview, html! {
+        (user_box(r, id, user, users.mentions(id), 2))
+        script type="application/ld+json" { (PreEscaped(person_json_ld(r, id, user))) }
+        @if !mentioned_by.is_empty() {
+            div.mentioned-by {
+                h3 { "Mentioned by" }
+                ul {
+                    @for &(other_id, other) in &mentioned_by {
+                        li {
+                            a href=(url!(r, "user", "id" => other_id)) {
+                                (user_title(other_id, Some(other)))
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        @if !similar.is_empty() {
+            div.similar {
+                h3 { "Similar Rustaceans" }
+                ul {
+                    @for &(other_id, other) in &similar {
+                        li {
+                            a href=(url!(r, "user", "id" => other_id)) {
+                                (user_title(other_id, Some(other)))
+                            }
+                            @let shared = shared_channels(user, other);
+                            @if !shared.is_empty() {
+                                " — also in "
+                                @for (i, channel) in shared.iter().enumerate() {
+                                    @if i > 0 { ", " }
+                                    "#" (channel)
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        div.qr {
+            img src=(url!(r, "user_qr", "id" => id)) alt="A QR code linking to this page"
+                width="200" height="200";
+            p.download {
+                "Download a QR code of "
+                a href=(url!(r, "user_qr", "id" => id)) download={ (id) ".svg" } { "this page" }
+                " or "
+                a href=(url!(r, "user_qr", "id" => id, "of" => "vcard"))
+                    download={ (id) "-vcard.svg" } { "their vCard" }
+            }
+        }
+        details.embed {
+            summary { "Put this on your site" }
+            img src=(url!(r, "user_badge", "id" => id)) alt="Find me on Karkinos";
+            pre {
+                code {
+                    "<a href=\"" (url!(r, "user", "id" => id)) "\">"
+                    "<img src=\"" (url!(r, "user_badge", "id" => id)) "\" "
+                    "alt=\"Find me on Karkinos\"></a>"
+                }
+            }
+            p {
+                "Or embed "
+                a href=(url!(r, "embed_user", "id" => id)) { "the whole card" }
+                ". Sites that support oEmbed will do this for you."
+            }
+        }
+        @if !user.irc_channels.is_empty() {
+            p.neighbourhood {
+                a href=(url!(r, "user_neighbourhood", "id" => id)) {
+                    "Which channels overlap with theirs?"
+                }
+            }
+        }
+        p.origins {
+            "From "
+            @for (i, origin) in origins.iter().enumerate() {
+                @if i > 0 { " + " }
+                (origin)
+            }
+            @if let (Some(first), Some(last)) = (revisions.last(), revisions.first()) {
+                br;
+                "Added " (first.time.format("%Y-%m-%d"))
+                ", last updated "
+                span title=(last.summary) { (last.time.format("%Y-%m-%d")) }
+                " ("
+                a href=(url!(r, "user_history", "id" => id)) { "history" }
+                ")"
+            }
+        }
+    })
 }
 
-fn user_title(id: &str, user: Option<&User>) -> String {
+/// Someone's details on their own, to be shown in a frame on another site.
+pub fn embed(r: &Urls, id: &str, user: &User, mentions: &[String]) -> Markup {
+    html! {
+        (DOCTYPE)
+        html {
+            meta charset="utf-8";
+            title { (user_title(id, Some(user))) " - Karkinos" }
+            link rel="stylesheet" href=(url!(r, "static", "path" => "styles.css"));
+            // Open links in the page we're embedded in, not the frame
+            base target="_blank";
+            body.embed {
+                (user_box(r, id, user, mentions, 3))
+                p.via {
+                    a href=(url!(r, "user", "id" => id)) {
+                        span.thecrab { "🦀" }
+                        " More on Karkinos"
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A small "find me on Karkinos" image, for linking to someone's profile.
+pub fn badge(id: &str) -> String {
+    const LABEL: &'static str = "find me on Karkinos";
+    // There's no way to measure text here, so guess from the usual width of
+    // an 11px sans-serif character
+    let width = |text: &str| text.chars().count() as u32 * 7 + 12;
+    let (label_width, id_width) = (width(LABEL), width(id));
+    html! {
+        svg xmlns="http://www.w3.org/2000/svg" width=(label_width + id_width) height="20"
+            role="img" aria-label={ (LABEL) ": " (id) } {
+            title { (LABEL) ": " (id) }
+            rect width=(label_width) height="20" fill="#555" {}
+            rect x=(label_width) width=(id_width) height="20" fill="#b7410e" {}
+            g fill="#fff" text-anchor="middle" font-family="Verdana,DejaVu Sans,sans-serif"
+                font-size="11" {
+                text x=(label_width / 2) y="14" { (LABEL) }
+                text x=(label_width + id_width / 2) y="14" { (id) }
+            }
+        }
+    }.into_string()
+}
+
+/// Returns the channels that both people are in.
+fn shared_channels<'a>(user: &User, other: &'a User) -> Vec<&'a str> {
+    other.irc_channels.iter()
+        .filter(|c| user.irc_channels.iter().any(|d| d.to_lowercase() == c.to_lowercase()))
+        .map(|c| &c[..])
+        .collect()
+}
+
+pub fn user_history(
+    r: &Urls, id: &str, user: Option<&User>, changes: &[(&Revision, Vec<FieldChange>)]) -> Markup
+{
+    let title = format!("History of {}", user_title(id, user));
+    layout(r, Some(&title), html! {
+        p {
+            a href=(url!(r, "user", "id" => id)) { "‹ Back to profile" }
+        }
+        @for &(revision, ref fields) in changes {
+            h3 {
+                a href={ (UPSTREAM_URL) "/commit/" (revision.commit) } {
+                    (revision.summary)
+                }
+            }
+            p.origins { (revision.time.format("%Y-%m-%d %H:%M UTC")) }
+            @if fields.is_empty() {
+                p { "No fields changed." }
+            } @else {
+                table.diff {
+                    @for field in fields {
+                        tr {
+                            th { (field.field) }
+                            td {
+                                @if let Some(ref old) = field.old {
+                                    del { (old) }
+                                    " "
+                                }
+                                @if let Some(ref new) = field.new {
+                                    ins { (new) }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+pub fn user_title(id: &str, user: Option<&User>) -> String {
     if let Some(name) = user.and_then(|user| user.name.as_ref()) {
         format!("{} ({})", name, id)
     } else {
@@ -174,88 +468,173 @@ fn user_title(id: &str, user: Option<&User>) -> String {
     }
 }
 
-fn user_box(id: &str, user: &User, demote_headers: u32) -> Markup {
+/// Collapses a Markdown note into a single line of at most `max_chars`
+/// characters, for use in link previews.
+fn summarize(notes: &str, max_chars: usize) -> String {
+    let words: Vec<&str> = notes.split_whitespace().collect();
+    let text = words.join(" ");
+    if text.chars().count() <= max_chars {
+        text
+    } else {
+        let mut result: String = text.chars().take(max_chars - 1).collect();
+        result.push('…');
+        result
+    }
+}
+
+/// The links to this person elsewhere on the web.
+fn same_as(id: &str, user: &User) -> Vec<String> {
+    let mut links = vec![Service::GitHub.url(id)];
+    if let Some(ref x) = user.twitter {
+        links.push(Service::Twitter.url(x));
+    }
+    if let Some(ref x) = user.reddit {
+        links.push(Service::Reddit.url(x));
+    }
+    if let Some(ref x) = user.discourse {
+        links.push(Service::Discourse.url(x));
+    }
+    links.extend(user.blog.iter().cloned());
+    links.extend(user.website.iter().cloned());
+    links
+}
+
+/// A schema.org `Person`, for search engines.
+#[derive(Serialize)]
+struct Person<'a> {
+    #[serde(rename = "@context")]
+    context: &'static str,
+    #[serde(rename = "@type")]
+    kind: &'static str,
+    name: &'a str,
+    #[serde(rename = "alternateName")]
+    alternate_name: &'a str,
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(rename = "sameAs")]
+    same_as: Vec<String>,
+}
+
+fn person_json_ld(r: &Urls, id: &str, user: &User) -> String {
+    let person = Person {
+        context: "http://schema.org",
+        kind: "Person",
+        name: user.name.as_ref().map_or(id, |name| &name[..]),
+        alternate_name: id,
+        url: url!(r, "user", "id" => id),
+        email: user.email.as_ref().map(|email| format!("mailto:{}", email)),
+        same_as: same_as(id, user),
+    };
+    // Don't let a stray `</script>` end the element early
+    serde_json::to_string(&person).unwrap().replace("</", "<\\/")
+}
+
+fn user_box(r: &Urls, id: &str, user: &User, mentions: &[String], demote_headers: u32) -> Markup {
     html! {
-        table {
-            tr {
-                th { "GitHub" }
-                td {
-                    a href={ "https://github.com/" (id) } { (id) }
-                }
-            }
-            @if let Some(ref nick) = user.irc {
+        div.h-card {
+            data.p-name value=(user.name.as_ref().map_or(id, |name| &name[..])) {}
+            data.u-uid value=(url!(r, "user", "id" => id)) {}
+            img.avatar.u-photo src=(url!(r, "avatar", "id" => id)) alt="" width="80" height="80";
+            table {
                 tr {
-                    th { "IRC" }
+                    th { "GitHub" }
                     td {
-                        (nick)
-                        @if !user.irc_channels.is_empty() {
-                            " on "
-                            @for (i, channel) in user.irc_channels.iter().enumerate() {
-                                @if i > 0 { ", " }
-                                a href={ "irc://irc.mozilla.org/" (channel) } {
-                                    "#" (channel)
+                        a.u-url.p-nickname href=(Service::GitHub.url(id)) { (id) }
+                    }
+                }
+                @if let Some(ref nick) = user.irc {
+                    tr {
+                        th { "IRC" }
+                        td {
+                            (nick)
+                            @if !user.irc_channels.is_empty() {
+                                " on "
+                                @for (i, channel) in user.irc_channels.iter().enumerate() {
+                                    @if i > 0 { ", " }
+                                    a href=(url!(r, "channel",
+                                                 "name" => channel.to_lowercase())) {
+                                        "#" (channel)
+                                    }
                                 }
                             }
                         }
                     }
                 }
-            }
-            @if let Some(ref x) = user.discourse {
-                tr {
-                    th { "Discourse" }
-                    td {
-                        a href={ "https://users.rust-lang.org/users/" (x) } { (x) }
+                @if let Some(ref x) = user.discourse {
+                    tr {
+                        th { "Discourse" }
+                        td {
+                            a.u-url href=(Service::Discourse.url(x)) { (x) }
+                        }
                     }
                 }
-            }
-            @if let Some(ref x) = user.reddit {
-                tr {
-                    th { "Reddit" }
-                    td {
-                        a href={ "https://reddit.com/user/" (x) } { (x) }
+                @if let Some(ref x) = user.reddit {
+                    tr {
+                        th { "Reddit" }
+                        td {
+                            a.u-url href=(Service::Reddit.url(x)) { (x) }
+                        }
                     }
                 }
-            }
-            @if let Some(ref x) = user.twitter {
-                tr {
-                    th { "Twitter" }
-                    td {
-                        a href={ "https://twitter.com/" (x) } { (x) }
+                @if let Some(ref x) = user.twitter {
+                    tr {
+                        th { "Twitter" }
+                        td {
+                            a.u-url href=(Service::Twitter.url(x)) { (x) }
+                        }
                     }
                 }
-            }
-            @if let Some(ref x) = user.website {
-                tr {
-                    th { "Website" }
-                    td {
-                        a href=(x) { (x) }
+                @if let Some(ref x) = user.website {
+                    tr {
+                        th { "Website" }
+                        td {
+                            a.u-url href=(x) { (x) }
+                        }
                     }
                 }
-            }
-            @if let Some(ref x) = user.blog {
-                tr {
-                    th { "Blog" }
-                    td {
-                        a href=(x) { (x) }
+                @if let Some(ref x) = user.blog {
+                    tr {
+                        th { "Blog" }
+                        td {
+                            a.u-url href=(x) { (x) }
+                        }
                     }
                 }
-            }
-            @if let Some(ref x) = user.email {
-                tr {
-                    th { "Email" }
-                    td {
-                        a href={ "mailto:" (x) } { (x) }
+                @if let Some(ref x) = user.email {
+                    tr {
+                        th { "Email" }
+                        td {
+                            a.u-email href={ "mailto:" (x) } { (x) }
+                        }
+                    }
+                }
+                @for (key, value) in &user.extra {
+                    tr {
+                        th { (key) }
+                        td {
+                            @if let Some(s) = value.as_str() {
+                                (s)
+                            } @else {
+                                (value)
+                            }
+                        }
                     }
                 }
             }
+            @if let Some(ref x) = user.notes {
+                div.notes.p-note { (Markdown { urls: r, text: x, mentions, demote_headers }) }
+            }
         }
-        @if let Some(ref x) = user.notes {
-            div.notes { (Markdown { text: x, demote_headers }) }
+        p.download {
+            a href=(url!(r, "user", "id" => format!("{}.vcf", id))) {
+                "Add to address book (vCard)"
+            }
         }
     }
 }
 
-pub fn user_error(r: &Request, id: &str, error: &str) -> Markup {
+pub fn user_error(r: &Urls, id: &str, error: &str) -> Markup {
     layout(r, Some(id), html! {
         p {
             "The user "
@@ -268,7 +647,7 @@ pub fn user_error(r: &Request, id: &str, error: &str) -> Markup {
     })
 }
 
-pub fn user_not_found(r: &Request, id: &str) -> Markup {
+pub fn user_not_found(r: &Urls, id: &str) -> Markup {
     layout(r, Some(id), html! {
         p {
             "The user "
@@ -276,19 +655,378 @@ pub fn user_not_found(r: &Request, id: &str) -> Markup {
             " could not be found."
         }
         p {
-            a href="/" { "‹ Back to home page" }
+            a href=(url!(r, "home")) { "‹ Back to home page" }
+        }
+    })
+}
+
+/// How many entries are shown on each page of the directory.
+pub const DIRECTORY_PAGE_SIZE: usize = 100;
+
+/// Lists the entries, grouped by the first letter that they're sorted by.
+///
+/// The entries should already be sorted in the given order. If `page` is
+/// given, only that page of entries is shown, with links to the others.
+pub fn directory(
+    r: &Urls, entries: &[(&str, Result<&User, &str>)], order: SortOrder, page: Option<usize>)
+    -> Markup
+{
+    let page_url = |page: usize| {
+        url!(r, "users", "sort" => order.name(), "page" => (page + 1).to_string())
+    };
+    // Where each letter starts, so that we can jump to it
+    let mut letters = BTreeMap::new();
+    for (i, &(id, user)) in entries.iter().enumerate() {
+        letters.entry(initial(&order.key(id, user))).or_insert(i);
+    }
+    let (start, end) = match page {
+        Some(page) => {
+            let start = page.checked_mul(DIRECTORY_PAGE_SIZE)
+                .map_or(entries.len(), |start| entries.len().min(start));
+            (start, entries.len().min(start + DIRECTORY_PAGE_SIZE))
+        },
+        None => (0, entries.len()),
+    };
+    let page_count = (entries.len() + DIRECTORY_PAGE_SIZE - 1) / DIRECTORY_PAGE_SIZE;
+    let mut groups = BTreeMap::new();
+    for &(id, user) in &entries[start..end] {
+        groups.entry(initial(&order.key(id, user))).or_insert_with(Vec::new).push((id, user));
+    }
+    let pager = html! {
+        @if let Some(page) = page {
+            p.pager {
+                @if page > 0 {
+                    a href=(page_url(page - 1)) rel="prev" { "‹ Previous" }
+                    " "
+                }
+                "Page " (page + 1) " of " (page_count)
+                @if page + 1 < page_count {
+                    " "
+                    a href=(page_url(page + 1)) rel="next" { "Next ›" }
+                }
+            }
+        }
+    };
+    layout(r, Some("All Rustaceans"), html! {
+        @if page.is_some() {
+            p.sort {
+                "Sort by "
+                @for (i, &other) in [SortOrder::Id, SortOrder::Name].iter().enumerate() {
+                    @if i > 0 { " · " }
+                    @if other == order {
+                        strong { (other.label()) }
+                    } @else {
+                        a href=(url!(r, "users", "sort" => other.name())) { (other.label()) }
+                    }
+                }
+            }
+        }
+        p.letters {
+            @for (&letter, &i) in &letters {
+                @if page.is_some() {
+                    a href={ (page_url(i / DIRECTORY_PAGE_SIZE)) "#" (letter_anchor(letter)) } {
+                        (letter)
+                    }
+                } @else {
+                    a href={ "#" (letter_anchor(letter)) } { (letter) }
+                }
+                " "
+            }
+        }
+        (pager)
+        @for (letter, entries) in &groups {
+            h3 id=(letter_anchor(*letter)) { (letter) }
+            ul.directory {
+                @for &(id, user) in entries {
+                    li {
+                        a href=(url!(r, "user", "id" => id)) { (user_title(id, user.ok())) }
+                        @if user.is_err() {
+                            " "
+                            span.broken title="This entry could not be parsed" { "(broken)" }
+                        }
+                    }
+                }
+            }
+        }
+        (pager)
+    })
+}
+
+/// Returns the letter that an entry is filed under, or `#` if it doesn't
+/// start with one.
+fn initial(text: &str) -> char {
+    match text.chars().next() {
+        Some(c) if c.is_alphabetic() => c.to_uppercase().next().unwrap_or(c),
+        _ => '#',
+    }
+}
+
+fn letter_anchor(letter: char) -> String {
+    if letter == '#' {
+        "other".to_string()
+    } else {
+        format!("letter-{}", letter.to_lowercase())
+    }
+}
+
+pub fn channels(r: &Urls, channels: &BTreeMap<String, Vec<String>>, graph: &ChannelGraph)
+    -> Markup
+{
+    layout(r, Some("IRC channels"), html! {
+        div.graph {
+            (channel_graph(r, graph, &[]))
+            p {
+                "Lines join channels that have people in common. "
+                a href=(url!(r, "channels_svg")) { "Download the graph" }
+            }
+        }
+        table {
+            @for (name, members) in channels {
+                tr {
+                    th {
+                        a href=(url!(r, "channel", "name" => name)) { "#" (name) }
+                    }
+                    td {
+                        (members.len())
+                        @if members.len() == 1 { " person" } @else { " people" }
+                    }
+                }
+            }
+        }
+    })
+}
+
+pub fn channel(
+    r: &Urls, name: &str, members: &[(&str, &User)], overlapping: &[(&str, usize)]) -> Markup
+{
+    let title = format!("#{}", name);
+    layout(r, Some(&title), html! {
+        table {
+            @for &(id, user) in members {
+                tr {
+                    th {
+                        a href=(url!(r, "user", "id" => id)) { (user_title(id, Some(user))) }
+                    }
+                    td {
+                        @if let Some(ref nick) = user.irc {
+                            (nick)
+                        }
+                    }
+                }
+            }
+        }
+        @if !overlapping.is_empty() {
+            h3 { "Shares people with" }
+            (overlap_table(r, overlapping))
+        }
+        p.download {
+            a href=(url!(r, "vcards", "channel" => name)) { "Download these people as vCards" }
+        }
+        p {
+            a href=(url!(r, "channels")) { "‹ All channels" }
+        }
+    })
+}
+
+/// Shows which channels someone's channels overlap with, so that there's
+/// someone to ask for an introduction.
+pub fn neighbourhood(r: &Urls, id: &str, user: &User, graph: &ChannelGraph) -> Markup {
+    let title = format!("Channels around {}", user_title(id, Some(user)));
+    layout(r, Some(&title), html! {
+        div.graph {
+            (channel_graph(r, graph, &user.irc_channels))
+        }
+        @for channel in &user.irc_channels {
+            @let name = channel.to_lowercase();
+            h3 {
+                a href=(url!(r, "channel", "name" => name)) { "#" (channel) }
+            }
+            @let overlapping = graph.overlapping(&name);
+            @if overlapping.is_empty() {
+                p { "Nobody here is in any other channel." }
+            } @else {
+                (overlap_table(r, &overlapping))
+            }
+        }
+        p {
+            a href=(url!(r, "user", "id" => id)) { "‹ Back to " (user_title(id, Some(user))) }
+        }
+    })
+}
+
+fn overlap_table(r: &Urls, overlapping: &[(&str, usize)]) -> Markup {
+    html! {
+        table {
+            @for &(name, count) in overlapping {
+                tr {
+                    th {
+                        a href=(url!(r, "channel", "name" => name)) { "#" (name) }
+                    }
+                    td {
+                        (count)
+                        @if count == 1 { " person" } @else { " people" }
+                        " in common"
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Draws the channels around a circle, sized by how many people are in
+/// them, with lines between channels that share people. The `highlight`ed
+/// channels are picked out in a different colour.
+pub fn channel_graph(r: &Urls, graph: &ChannelGraph, highlight: &[String]) -> Markup {
+    const SIZE: f64 = 720.0;
+    let positions = graph.circle(SIZE / 2.0 - 140.0);
+    let highlighted = |name: &str| highlight.iter().any(|c| c.to_lowercase() == name);
+    let centre = SIZE / 2.0;
+    let point = |(x, y): (f64, f64)| (format!("{:.1}", centre + x), format!("{:.1}", centre + y));
+    html! {
+        svg xmlns="http://www.w3.org/2000/svg" viewBox={ "0 0 " (SIZE) " " (SIZE) }
+            width=(SIZE) height=(SIZE) font-family="sans-serif" font-size="11" {
+            title { "IRC channels that share people" }
+            @for &(a, b, count) in &graph.overlaps {
+                @let (x1, y1) = point(positions[a]);
+                @let (x2, y2) = point(positions[b]);
+                line x1=(x1) y1=(y1) x2=(x2) y2=(y2) stroke="#7a5c3d" stroke-opacity="0.35"
+                    stroke-width=(format!("{:.1}", (count as f64).sqrt())) {
+                    title {
+                        "#" (graph.channels[a].0) " and #" (graph.channels[b].0) " share "
+                        (count) @if count == 1 { " person" } @else { " people" }
+                    }
+                }
+            }
+            @for (i, &(ref name, members)) in graph.channels.iter().enumerate() {
+                @let (x, y) = positions[i];
+                @let (cx, cy) = point((x, y));
+                @let (lx, ly) = point((x * 1.06, y * 1.06));
+                a href=(url!(r, "channel", "name" => name)) {
+                    circle cx=(cx) cy=(cy) r=(format!("{:.1}", 3.0 + 2.0 * (members as f64).sqrt()))
+                        fill=(if highlighted(name) { "#d9480f" } else { "#3d5a7a" }) {
+                        title {
+                            "#" (name) ": " (members)
+                            @if members == 1 { " person" } @else { " people" }
+                        }
+                    }
+                    text x=(lx) y=(ly) dy="0.35em"
+                        text-anchor=(if x < 0.0 { "end" } else { "start" })
+                        transform=(format!("rotate({:.1} {} {})", label_angle(x, y), lx, ly)) {
+                        "#" (name)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns how far to turn a label so that it points away from the centre
+/// of the circle, without ending up upside down.
+fn label_angle(x: f64, y: f64) -> f64 {
+    let angle = y.atan2(x).to_degrees();
+    if x < 0.0 { angle - 180.0 } else { angle }
+}
+
+/// A search page that runs entirely in the browser, for static builds.
+pub fn static_search(r: &Urls) -> Markup {
+    layout(r, Some("Search"), html! {
+        (search_form(r, ""))
+        div#results {}
+        script src=(url!(r, "static", "path" => "search-index.js")) {}
+        script src=(url!(r, "static", "path" => "search.js")) {}
+    })
+}
+
+pub fn admin_status(r: &Urls, status: &UpdateStatus, users: &Users) -> Markup {
+    layout(r, Some("Status"), html! {
+        table {
+            tr {
+                th { "Serving" }
+                td {
+                    @if let Some(ref commit) = status.last_good {
+                        code { (commit) }
+                    } @else {
+                        "unknown commit"
+                    }
+                }
+            }
+            tr {
+                th { "Entries" }
+                td { (users.len()) }
+            }
+            tr {
+                th { "Errors" }
+                td { (users.errors().count()) }
+            }
+        }
+        @if let Some(ref rejected) = status.rejected {
+            h3 { "Last rejected update" }
+            table {
+                tr {
+                    th { "Commit" }
+                    td { code { (rejected.commit) } }
+                }
+                tr {
+                    th { "Reason" }
+                    td { (rejected.reason) }
+                }
+            }
+            @if !rejected.errors.is_empty() {
+                ul {
+                    @for &(ref id, ref error) in &rejected.errors {
+                        li { strong { (id) } ": " (error) }
+                    }
+                }
+            }
+        } @else {
+            p { "No updates have been rejected." }
         }
     })
 }
 
 struct Markdown<'a> {
+    urls: &'a Urls,
     text: &'a str,
+    /// The people mentioned in the text, as found by `mention::index`.
+    mentions: &'a [String],
     demote_headers: u32,
 }
 
+impl<'a> Markdown<'a> {
+    /// Turns the people mentioned in some text into links to their pages.
+    fn link_mentions(&self, text: &str) -> Vec<Event<'static>> {
+        let found = mention::find(text, |word, at| {
+            self.mentions.iter()
+                .find(|id| if at { id.eq_ignore_ascii_case(word) } else { *id == word })
+                .map(|id| &id[..])
+        });
+        let mut events = Vec::new();
+        let mut last = 0;
+        for (range, id) in found {
+            if last < range.start {
+                events.push(Event::Text(text[last..range.start].to_string().into()));
+            }
+            let url = url!(self.urls, "user", "id" => id);
+            events.push(Event::Start(Tag::Link(url.clone().into(), "".into())));
+            events.push(Event::Text(text[range.clone()].to_string().into()));
+            events.push(Event::End(Tag::Link(url.into(), "".into())));
+            last = range.end;
+        }
+        if last < text.len() {
+            events.push(Event::Text(text[last..].to_string().into()));
+        }
+        events
+    }
+}
+
 impl<'a> Render for Markdown<'a> {
     fn render(&self) -> Markup {
-        let parser = Parser::new_ext(self.text, pulldown_cmark::OPTION_ENABLE_TABLES);
+        // Only link the text that `mention::index` looked at
+        let parser = mention::parse(self.text).flat_map(|(event, linkable)| match event {
+            Event::Text(ref text) if linkable && !self.mentions.is_empty() =>
+                self.link_mentions(text),
+            event => vec![event],
+        });
         // Demote headers
         let parser = parser.map(|event| match event {
             Event::Start(Tag::Header(level)) =>
@@ -303,3 +1041,51 @@ impl<'a> Render for Markdown<'a> {
         PreEscaped(safe_html)
     }
 }
+
+/// Links to a made-up site, for testing.
+#[cfg(test)]
+pub struct TestUrls;
+
+#[cfg(test)]
+impl Urls for TestUrls {
+    fn current(&self) -> String {
+        "https://karkinos.example/".to_string()
+    }
+
+    fn route(&self, route: &str, params: &[(&str, &str)]) -> String {
+        let path = match route {
+            "home" => String::new(),
+            "user" => format!("user/{}", params[0].1),
+            "user_card" => format!("user/{}/card.png", params[0].1),
+            "embed_user" => format!("embed/user/{}", params[0].1),
+            _ => panic!("unexpected route {}", route),
+        };
+        format!("https://karkinos.example/{}", path)
+    }
+}
+
+#[test]
+fn badges() {
+    let svg = badge("ferris");
+    assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"199\""));
+    assert!(svg.contains("<title>find me on Karkinos: ferris</title>"));
+    assert!(svg.contains(">ferris</text>"));
+    assert!(svg.ends_with("</svg>"));
+}
+
+#[test]
+fn mention_links() {
+    let mentions = ["crab-42".to_string(), "ferris".to_string()];
+    let markdown = Markdown {
+        urls: &TestUrls,
+        text: "Thanks @Ferris and crab-42! Not `@ferris` or [@ferris](http://example.com/).",
+        mentions: &mentions,
+        demote_headers: 0,
+    };
+    let html = markdown.render().into_string();
+    assert_eq!(html.matches("href=\"https://karkinos.example/user/ferris\"").count(), 1);
+    assert!(html.contains(">@Ferris</a>"));
+    assert!(html.contains("href=\"https://karkinos.example/user/crab-42\""));
+    assert!(html.contains("<code>@ferris</code>"));
+    assert!(html.contains("href=\"http://example.com/\""));
+}
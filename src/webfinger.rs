@@ -0,0 +1,142 @@
+//! WebFinger (RFC 7033), for looking people up by identifier.
+
+use models::{Service, User, Users};
+
+const PROFILE_PAGE: &'static str = "http://webfinger.net/rel/profile-page";
+const BLOG: &'static str = "http://webfinger.net/rel/blog";
+
+/// Extra fields that might hold a fediverse handle, like `@ferris@example.org`.
+const FEDIVERSE_FIELDS: &'static [&'static str] = &["fediverse", "mastodon"];
+
+/// A JSON Resource Descriptor.
+#[derive(Debug, Serialize)]
+pub struct Jrd {
+    subject: String,
+    aliases: Vec<String>,
+    links: Vec<Link>,
+}
+
+#[derive(Debug, Serialize)]
+struct Link {
+    rel: &'static str,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    content_type: Option<&'static str>,
+    href: String,
+}
+
+/// Finds the user that a resource refers to, and describes them.
+///
+/// The resource can be an `acct:` URI (`acct:<id>@<host>`) or the address of
+/// their profile page. If any `rels` are given, only links with those
+/// relations are included.
+pub fn lookup(
+    users: &Users, resource: &str, rels: &[String], profile_url: &Fn(&str) -> String)
+    -> Option<Jrd>
+{
+    let id = if resource.starts_with("acct:") {
+        let account = &resource["acct:".len()..];
+        // GitHub usernames can't have an `@` in them, so split on the last one
+        let at = account.rfind('@')?;
+        let (id, host) = (&account[..at], &account[at + 1..]);
+        if !is_our_host(host, &profile_url(id)) {
+            return None;
+        }
+        id
+    } else {
+        let id = resource.rsplit('/').next()?;
+        if resource != profile_url(id) {
+            return None;
+        }
+        id
+    };
+    let user = users.get(id)?.ok()?;
+    let profile = profile_url(id);
+    let mut jrd = Jrd {
+        subject: resource.to_string(),
+        aliases: vec![profile.clone()],
+        links: links(id, user, profile),
+    };
+    if let Some(handle) = fediverse_handle(user) {
+        jrd.aliases.push(format!("acct:{}", handle));
+    }
+    if !rels.is_empty() {
+        jrd.links.retain(|link| rels.iter().any(|rel| rel == link.rel));
+    }
+    Some(jrd)
+}
+
+/// Checks whether the host in an `acct:` URI is the one that profile pages
+/// are served from. The port can be left out.
+fn is_our_host(host: &str, profile_url: &str) -> bool {
+    let ours = match profile_url.find("://") {
+        Some(i) => profile_url[i + 3..].split('/').next().unwrap_or(""),
+        None => return false,
+    };
+    let without_port = ours.split(':').next().unwrap_or(ours);
+    host.eq_ignore_ascii_case(ours) || host.eq_ignore_ascii_case(without_port)
+}
+
+fn links(id: &str, user: &User, profile: String) -> Vec<Link> {
+    let mut links = vec![
+        Link { rel: PROFILE_PAGE, content_type: Some("text/html"), href: profile },
+        Link { rel: PROFILE_PAGE, content_type: Some("text/html"),
+               href: Service::GitHub.url(id) },
+    ];
+    if let Some(ref blog) = user.blog {
+        links.push(Link { rel: BLOG, content_type: Some("text/html"), href: blog.clone() });
+    }
+    if let Some(handle) = fediverse_handle(user) {
+        if let Some(at) = handle.find('@') {
+            let (name, server) = (&handle[..at], &handle[at + 1..]);
+            links.push(Link {
+                rel: PROFILE_PAGE,
+                content_type: Some("text/html"),
+                href: format!("https://{}/@{}", server, name),
+            });
+        }
+    }
+    links
+}
+
+/// Returns the user's fediverse handle, as `user@server` without a leading
+/// `@`, if they've told us one.
+fn fediverse_handle(user: &User) -> Option<String> {
+    FEDIVERSE_FIELDS.iter()
+        .filter_map(|field| user.extra.get(*field))
+        .filter_map(|value| value.as_str())
+        .map(|handle| handle.trim().trim_left_matches('@'))
+        .find(|handle| {
+            let parts: Vec<&str> = handle.split('@').collect();
+            parts.len() == 2 && parts.iter().all(|part| !part.is_empty())
+        })
+        .map(str::to_string)
+}
+
+#[test]
+fn resources() {
+    let users = Users::from_json(&[
+        ("ferris", r#"{ "name": "Ferris", "mastodon": "@ferris@crab.example" }"#),
+    ]);
+    let profile_url = |id: &str| format!("https://karkinos.example:8344/user/{}", id);
+    let find = |resource: &str| lookup(&users, resource, &[], &profile_url);
+
+    let jrd = find("acct:ferris@karkinos.example").unwrap();
+    assert_eq!(jrd.subject, "acct:ferris@karkinos.example");
+    assert_eq!(jrd.aliases, vec![
+        "https://karkinos.example:8344/user/ferris".to_string(),
+        "acct:ferris@crab.example".to_string(),
+    ]);
+    assert_eq!(jrd.links.len(), 3);
+    assert!(find("acct:ferris@KARKINOS.example:8344").is_some());
+    assert!(find("acct:ferris@elsewhere.example").is_none());
+    assert!(find("acct:ferris").is_none());
+    assert!(find("acct:corro@karkinos.example").is_none());
+
+    assert!(find("https://karkinos.example:8344/user/ferris").is_some());
+    assert!(find("https://elsewhere.example/user/ferris").is_none());
+    assert!(find("https://karkinos.example:8344/channel/ferris").is_none());
+
+    let rels = [BLOG.to_string()];
+    assert!(lookup(&users, "acct:ferris@karkinos.example", &rels, &profile_url).unwrap()
+        .links.is_empty());
+}
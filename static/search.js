@@ -0,0 +1,51 @@
+// Searches a static snapshot of the site, using the index in search-index.js.
+// This works like `SearchIndex::query`, minus the spelling correction.
+(function () {
+    var query = new URLSearchParams(location.search).get('q')
+    if (!query) return
+    document.getElementById('q').value = query
+
+    function fold(text) {
+        return text.normalize('NFKD').toLowerCase()
+    }
+
+    var words = fold(query).split(/[^\w]+/).filter(function (word) { return word })
+    var scores = null
+    words.forEach(function (word) {
+        // Match words by prefix, like the server does
+        var matches = {}
+        Object.keys(searchIndex.words).forEach(function (indexed) {
+            if (indexed.lastIndexOf(word, 0) !== 0) return
+            searchIndex.words[indexed].forEach(function (entry) {
+                matches[entry[0]] = (matches[entry[0]] || 0) + entry[1]
+            })
+        })
+        if (scores === null) {
+            scores = matches
+        } else {
+            // Only keep entries that match every word
+            Object.keys(scores).forEach(function (i) {
+                if (matches[i]) scores[i] *= matches[i]
+                else delete scores[i]
+            })
+        }
+    })
+
+    var results = document.getElementById('results')
+    var ranked = Object.keys(scores || {}).sort(function (a, b) { return scores[b] - scores[a] })
+    if (!ranked.length) {
+        results.textContent = 'No results found.'
+        return
+    }
+    var list = document.createElement('ul')
+    ranked.slice(0, 50).forEach(function (i) {
+        var user = searchIndex.users[i]
+        var link = document.createElement('a')
+        link.href = 'user/' + encodeURIComponent(user[0]) + '.html'
+        link.textContent = user[1]
+        var item = document.createElement('li')
+        item.appendChild(link)
+        list.appendChild(item)
+    })
+    results.appendChild(list)
+})()